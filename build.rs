@@ -0,0 +1,409 @@
+//! Validates the embedded vDSO blob and generates its layout constants.
+//!
+//! `embed.rs` includes `vdso/vdso_<arch>.so` verbatim, so any mismatch between
+//! that blob and the constants in `config.rs` only shows up as crashes in user
+//! processes. This script parses the blob at build time, refuses images that
+//! cannot be mapped safely, and writes `vdso_config.rs` into `OUT_DIR` for the
+//! arch `config.rs` to include.
+
+use std::{env, fmt::Write as _, fs, path::PathBuf};
+
+use xmas_elf::{
+    ElfFile,
+    dynamic::Tag,
+    header::{Class, Data},
+    program::{SegmentData, Type},
+    sections::{SHF_EXECINSTR, SHN_UNDEF, SectionData},
+    symbol_table::Entry,
+};
+
+/// Per-arch expectations for the embedded vDSO.
+struct ArchSpec {
+    arch: &'static str,
+    /// ELF `e_machine` of the blob.
+    machine: u16,
+    /// Version the blob defines its symbols in. The vvar layout in the crate
+    /// is the one the vDSO sources of that ABI were built against.
+    abi_version: &'static str,
+    /// Symbols that must be exported.
+    required: &'static [&'static str],
+    /// Symbol of the signal return trampoline, if the arch uses one.
+    sigreturn: Option<&'static str>,
+    /// Size of the vvar area that layout spans, in bytes. The size decoded
+    /// from the image must match.
+    vvar_size: u64,
+    /// Pages of data in that layout, whatever the page size. An image with
+    /// fewer vvar pages was built for smaller pages than the kernel uses.
+    data_pages: u64,
+}
+
+const SPECS: &[ArchSpec] = &[
+    ArchSpec {
+        arch: "x86_64",
+        machine: 62,
+        abi_version: "LINUX_2.6",
+        required: &[
+            "__vdso_clock_gettime",
+            "__vdso_gettimeofday",
+            "__vdso_clock_getres",
+            "__vdso_time",
+            "__vdso_getcpu",
+        ],
+        sigreturn: None,
+        vvar_size: 0x6000,
+        data_pages: 2,
+    },
+    ArchSpec {
+        arch: "aarch64",
+        machine: 183,
+        abi_version: "LINUX_2.6.39",
+        required: &[
+            "__kernel_clock_gettime",
+            "__kernel_gettimeofday",
+            "__kernel_clock_getres",
+        ],
+        sigreturn: Some("__kernel_rt_sigreturn"),
+        vvar_size: 0x4000,
+        data_pages: 4,
+    },
+    ArchSpec {
+        arch: "riscv64",
+        machine: 243,
+        abi_version: "LINUX_4.15",
+        required: &[
+            "__vdso_clock_gettime",
+            "__vdso_gettimeofday",
+            "__vdso_clock_getres",
+            "__vdso_getcpu",
+        ],
+        sigreturn: Some("__vdso_rt_sigreturn"),
+        vvar_size: 0x4000,
+        data_pages: 1,
+    },
+    ArchSpec {
+        arch: "loongarch64",
+        machine: 258,
+        abi_version: "LINUX_5.10",
+        required: &[
+            "__vdso_clock_gettime",
+            "__vdso_gettimeofday",
+            "__vdso_clock_getres",
+            "__vdso_getcpu",
+        ],
+        sigreturn: Some("__vdso_rt_sigreturn"),
+        vvar_size: 0x14000,
+        data_pages: 1,
+    },
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let Some(spec) = SPECS.iter().find(|spec| spec.arch == arch) else {
+        // No vDSO is embedded for this arch.
+        return;
+    };
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let path = manifest_dir.join(format!("vdso/vdso_{arch}.so"));
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let bytes = fs::read(&path)
+        .unwrap_or_else(|e| panic!("failed to read vDSO image {}: {e}", path.display()));
+    // xmas-elf reads tables in place, so give it a suitably aligned copy.
+    let mut storage = vec![0u64; bytes.len().div_ceil(8)];
+    let aligned =
+        unsafe { core::slice::from_raw_parts_mut(storage.as_mut_ptr() as *mut u8, bytes.len()) };
+    aligned.copy_from_slice(&bytes);
+
+    let page_size = if env::var_os("CARGO_FEATURE_PAGE_SIZE_64K").is_some() {
+        0x10000
+    } else if env::var_os("CARGO_FEATURE_PAGE_SIZE_16K").is_some() {
+        0x4000
+    } else {
+        MIN_PAGE_SIZE
+    };
+
+    let config = match check_image(spec, aligned, page_size, image_hash(&bytes)) {
+        Ok(config) => config,
+        Err(e) => panic!("invalid vDSO image {}: {e}", path.display()),
+    };
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("vdso_config.rs");
+    fs::write(&out, config).unwrap();
+}
+
+/// Check the image against `spec` and the kernel's `page_size`, and render
+/// the generated config.
+fn check_image(spec: &ArchSpec, bytes: &[u8], page_size: u64, hash: u64) -> Result<String, String> {
+    let elf = ElfFile::new(bytes)?;
+
+    if elf.header.pt1.class() != Class::SixtyFour || elf.header.pt1.data() != Data::LittleEndian {
+        return Err("expected a 64-bit little-endian ELF".into());
+    }
+    let machine = u16::from_le_bytes([bytes[18], bytes[19]]);
+    if machine != spec.machine {
+        return Err(format!(
+            "e_machine is {machine}, expected {} for {}",
+            spec.machine, spec.arch
+        ));
+    }
+
+    for ph in elf.program_iter() {
+        match ph.get_type()? {
+            Type::Load => {
+                let flags = ph.flags();
+                if flags.is_write() && flags.is_execute() {
+                    return Err(format!("PT_LOAD at {:#x} is W+X", ph.virtual_addr()));
+                }
+                if flags.is_write() {
+                    return Err(format!("PT_LOAD at {:#x} is writable", ph.virtual_addr()));
+                }
+                // Images aligned for larger pages also suit smaller ones, but
+                // not the other way round.
+                let align = ph.align();
+                if (MIN_PAGE_SIZE..page_size).contains(&align)
+                    || ph.virtual_addr() % page_size != ph.offset() % page_size
+                {
+                    return Err(format!(
+                        "PT_LOAD at {:#x} is aligned for {align:#x} pages, but the kernel uses \
+                         {page_size:#x} pages; select the matching page-size feature",
+                        ph.virtual_addr()
+                    ));
+                }
+            }
+            Type::Dynamic => check_no_relocations(&elf, ph.get_data(&elf)?)?,
+            _ => {}
+        }
+    }
+
+    let SectionData::DynSymbolTable64(dynsym) = elf
+        .find_section_by_name(".dynsym")
+        .ok_or("no .dynsym section")?
+        .get_data(&elf)?
+    else {
+        return Err("unexpected .dynsym section type".into());
+    };
+    let find_symbol = |name: &str| {
+        dynsym.iter().find(|sym| {
+            sym.shndx() != SHN_UNDEF && sym.get_name(&elf).is_ok_and(|sym_name| sym_name == name)
+        })
+    };
+    for name in spec.required.iter().chain(&spec.sigreturn) {
+        if find_symbol(name).is_none() {
+            return Err(format!("required symbol `{name}` is missing"));
+        }
+    }
+    if find_symbol(spec.abi_version).is_none() {
+        return Err(format!(
+            "image does not define version {}; update the vvar layout in the crate",
+            spec.abi_version
+        ));
+    }
+
+    let vvar_size = vvar_size(spec, &elf)?;
+    if vvar_size != spec.vvar_size {
+        return Err(format!(
+            "image references a vvar area of {vvar_size:#x} bytes, but the layout for {} spans \
+             {:#x}",
+            spec.abi_version, spec.vvar_size
+        ));
+    }
+    if vvar_size % page_size != 0 {
+        return Err(format!(
+            "vvar area of {vvar_size:#x} bytes does not fill whole {page_size:#x} pages"
+        ));
+    }
+    if vvar_size / page_size < spec.data_pages {
+        return Err(format!(
+            "vvar area of {vvar_size:#x} bytes holds only {} pages of {page_size:#x} bytes, but \
+             the vvar data takes {}; the image was built for smaller pages",
+            vvar_size / page_size,
+            spec.data_pages
+        ));
+    }
+
+    let mut config = String::new();
+    writeln!(
+        config,
+        "// Generated by build.rs from vdso/vdso_{}.so.",
+        spec.arch
+    )
+    .unwrap();
+    writeln!(config).unwrap();
+    writeln!(
+        config,
+        "/// Number of vvar pages mapped right below the vDSO."
+    )
+    .unwrap();
+    writeln!(
+        config,
+        "pub const VVAR_PAGES: usize = {};",
+        vvar_size / page_size
+    )
+    .unwrap();
+    writeln!(config).unwrap();
+    writeln!(config, "/// ELF `e_machine` of the vDSO image.").unwrap();
+    writeln!(config, "pub const ELF_MACHINE: u16 = {};", spec.machine).unwrap();
+    writeln!(config).unwrap();
+    writeln!(config, "/// FNV-1a hash of the vDSO image bytes.").unwrap();
+    writeln!(config, "pub const IMAGE_HASH: u64 = {hash:#018x};").unwrap();
+    if let Some(name) = spec.sigreturn {
+        let offset = find_symbol(name).unwrap().value();
+        writeln!(config).unwrap();
+        writeln!(config, "/// Offset of `{name}` within the vDSO image.").unwrap();
+        writeln!(
+            config,
+            "pub const SIGRETURN_SYM_OFFSET: usize = {offset:#x};"
+        )
+        .unwrap();
+    }
+    Ok(config)
+}
+
+/// Smallest page size images are built for.
+const MIN_PAGE_SIZE: u64 = 0x1000;
+
+/// Size of the vvar area the image expects right below it.
+///
+/// The image does not record it, and stripped images carry no symbol for it
+/// either. But the code only reaches vvar through PC-relative references, and
+/// the lowest of them is the start of the area, so decode those.
+fn vvar_size(spec: &ArchSpec, elf: &ElfFile) -> Result<u64, String> {
+    let mut lowest = 0i64;
+    let mut found = |target: i64| lowest = lowest.min(target);
+    for section in elf.section_iter() {
+        if section.flags() & SHF_EXECINSTR == 0 {
+            continue;
+        }
+        let pc = section.address() as i64;
+        let code = section.raw_data(elf);
+        match spec.arch {
+            "x86_64" => x86_64_targets(pc, code, &mut found),
+            "aarch64" => aarch64_targets(pc, code, &mut found),
+            "riscv64" => riscv64_targets(pc, code, &mut found),
+            "loongarch64" => loongarch64_targets(pc, code, &mut found),
+            _ => unreachable!(),
+        }
+    }
+    if lowest == 0 {
+        return Err("no references below the image, cannot derive the vvar size".into());
+    }
+    Ok(lowest.unsigned_abs().next_multiple_of(MIN_PAGE_SIZE))
+}
+
+/// Sign-extend the low `bits` bits of `value`.
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as i64
+}
+
+/// `mov` and `lea` with a RIP-relative operand.
+fn x86_64_targets(pc: i64, code: &[u8], found: &mut impl FnMut(i64)) {
+    for (i, insn) in code.windows(6).enumerate() {
+        if matches!(insn[0], 0x8b | 0x8d) && insn[1] & 0xc7 == 0x05 {
+            let disp = i32::from_le_bytes(insn[2..6].try_into().unwrap());
+            found(pc + i as i64 + 6 + disp as i64);
+        }
+    }
+}
+
+/// `adr` and `adrp`.
+fn aarch64_targets(pc: i64, code: &[u8], found: &mut impl FnMut(i64)) {
+    for (i, insn) in code.chunks_exact(4).enumerate() {
+        let insn = u32::from_le_bytes(insn.try_into().unwrap());
+        if insn & 0x1f00_0000 != 0x1000_0000 {
+            continue;
+        }
+        let imm = sign_extend((insn >> 5 & 0x7ffff) << 2 | insn >> 29 & 3, 21);
+        let pc = pc + i as i64 * 4;
+        found(if insn >> 31 == 0 {
+            pc + imm
+        } else {
+            (pc & !0xfff) + (imm << 12)
+        });
+    }
+}
+
+/// `auipc` together with the `addi`, load or store completing the address.
+fn riscv64_targets(pc: i64, code: &[u8], found: &mut impl FnMut(i64)) {
+    let mut upper = [None; 32];
+    let mut i = 0;
+    while i + 4 <= code.len() {
+        if code[i] & 3 != 3 {
+            // Compressed instruction.
+            i += 2;
+            continue;
+        }
+        let insn = u32::from_le_bytes(code[i..i + 4].try_into().unwrap());
+        let rd = (insn >> 7 & 31) as usize;
+        let rs1 = (insn >> 15 & 31) as usize;
+        let offset = match insn & 0x7f {
+            // auipc
+            0x17 => {
+                upper[rd] = Some(pc + i as i64 + (sign_extend(insn >> 12, 20) << 12));
+                None
+            }
+            // addi, loads
+            0x13 if insn >> 12 & 7 == 0 => Some(sign_extend(insn >> 20, 12)),
+            0x03 | 0x07 => Some(sign_extend(insn >> 20, 12)),
+            // stores
+            0x23 | 0x27 => Some(sign_extend((insn >> 25) << 5 | insn >> 7 & 31, 12)),
+            _ => None,
+        };
+        if let Some(offset) = offset
+            && let Some(base) = upper[rs1].take()
+        {
+            found(base + offset);
+        }
+        i += 4;
+    }
+}
+
+/// `pcalau12i` and `pcaddu12i`.
+fn loongarch64_targets(pc: i64, code: &[u8], found: &mut impl FnMut(i64)) {
+    for (i, insn) in code.chunks_exact(4).enumerate() {
+        let insn = u32::from_le_bytes(insn.try_into().unwrap());
+        let imm = sign_extend(insn >> 5 & 0xfffff, 20) << 12;
+        let pc = pc + i as i64 * 4;
+        match insn >> 25 {
+            0b000_1101 => found((pc & !0xfff) + imm),
+            0b000_1110 => found(pc + imm),
+            _ => {}
+        }
+    }
+}
+
+/// 64-bit FNV-1a, matching `validate::image_hash` in the crate.
+fn image_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// The image is mapped as-is into every process, so nothing may need fixing
+/// up by a loader.
+fn check_no_relocations(elf: &ElfFile, data: SegmentData) -> Result<(), String> {
+    let SegmentData::Dynamic64(entries) = data else {
+        return Err("unexpected PT_DYNAMIC contents".into());
+    };
+    for entry in entries {
+        match entry.get_tag()? {
+            Tag::RelaSize | Tag::RelSize | Tag::RelrSize | Tag::PltRelSize
+                if entry.get_val()? != 0 =>
+            {
+                return Err(format!(
+                    "image has dynamic relocations ({:?})",
+                    entry.get_tag()?
+                ));
+            }
+            Tag::TextRel => return Err("image has text relocations".into()),
+            Tag::Needed => {
+                let name = elf.get_dyn_string(entry.get_val()? as u32)?;
+                return Err(format!("image depends on `{name}`"));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
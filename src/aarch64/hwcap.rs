@@ -0,0 +1,78 @@
+const HWCAP_FP: usize = 1 << 0;
+const HWCAP_ASIMD: usize = 1 << 1;
+const HWCAP_AES: usize = 1 << 3;
+const HWCAP_PMULL: usize = 1 << 4;
+const HWCAP_SHA1: usize = 1 << 5;
+const HWCAP_SHA2: usize = 1 << 6;
+const HWCAP_CRC32: usize = 1 << 7;
+const HWCAP_ATOMICS: usize = 1 << 8;
+const HWCAP_FPHP: usize = 1 << 9;
+const HWCAP_ASIMDHP: usize = 1 << 10;
+
+const HWCAP2_RNG: usize = 1 << 16;
+
+fn field(reg: u64, shift: u32) -> u64 {
+    (reg >> shift) & 0xf
+}
+
+fn id_aa64isar0() -> u64 {
+    let val: u64;
+    unsafe { core::arch::asm!("mrs {}, ID_AA64ISAR0_EL1", out(reg) val) };
+    val
+}
+
+fn id_aa64pfr0() -> u64 {
+    let val: u64;
+    unsafe { core::arch::asm!("mrs {}, ID_AA64PFR0_EL1", out(reg) val) };
+    val
+}
+
+/// `AT_HWCAP`: the Linux `HWCAP_*` bits derived from the ID registers.
+pub fn hwcap() -> usize {
+    let isar0 = id_aa64isar0();
+    let pfr0 = id_aa64pfr0();
+    let mut caps = 0;
+
+    match field(pfr0, 16) {
+        0 => caps |= HWCAP_FP,
+        1 => caps |= HWCAP_FP | HWCAP_FPHP,
+        _ => {}
+    }
+    match field(pfr0, 20) {
+        0 => caps |= HWCAP_ASIMD,
+        1 => caps |= HWCAP_ASIMD | HWCAP_ASIMDHP,
+        _ => {}
+    }
+    match field(isar0, 4) {
+        1 => caps |= HWCAP_AES,
+        2 => caps |= HWCAP_AES | HWCAP_PMULL,
+        _ => {}
+    }
+    if field(isar0, 8) != 0 {
+        caps |= HWCAP_SHA1;
+    }
+    if field(isar0, 12) != 0 {
+        caps |= HWCAP_SHA2;
+    }
+    if field(isar0, 16) != 0 {
+        caps |= HWCAP_CRC32;
+    }
+    if field(isar0, 20) >= 2 {
+        caps |= HWCAP_ATOMICS;
+    }
+    caps
+}
+
+/// `AT_HWCAP2`: `HWCAP2_RNG` if `RNDR` is implemented.
+pub fn hwcap2() -> usize {
+    if field(id_aa64isar0(), 60) != 0 {
+        HWCAP2_RNG
+    } else {
+        0
+    }
+}
+
+/// `AT_MINSIGSTKSZ` for a signal frame of `frame_size` bytes.
+pub fn minsigstksz(frame_size: usize) -> usize {
+    frame_size.next_multiple_of(16)
+}
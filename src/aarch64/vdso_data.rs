@@ -1,10 +1,16 @@
-use crate::vdso_time_data::VdsoTimeData;
+use crate::{PAGE_SIZE, config::VVAR_PAGES, timekeeping::Timekeeper, vdso_time_data::VdsoTimeData};
+
+/// Zeroes filling the rest of the vvar area, which is mapped to user space
+/// in full.
+const PAD_SIZE: usize = (VVAR_PAGES - 3) * PAGE_SIZE - size_of::<VdsoTimeData>();
+
 #[repr(C)]
 pub struct VdsoData {
     pub time_data: VdsoTimeData,
-    pub timen_data: [u8; 4096],
-    pub rng_data: [u8; 4096],
-    pub arch_data: [u8; 4096],
+    pub timen_data: [u8; PAGE_SIZE],
+    pub rng_data: [u8; PAGE_SIZE],
+    pub arch_data: [u8; PAGE_SIZE],
+    _pad: [u8; PAD_SIZE],
 }
 
 impl Default for VdsoData {
@@ -17,14 +23,15 @@ impl VdsoData {
     pub const fn new() -> Self {
         Self {
             time_data: VdsoTimeData::new(),
-            timen_data: [0u8; 4096],
-            rng_data: [0u8; 4096],
-            arch_data: [0u8; 4096],
+            timen_data: [0u8; PAGE_SIZE],
+            rng_data: [0u8; PAGE_SIZE],
+            arch_data: [0u8; PAGE_SIZE],
+            _pad: [0u8; PAD_SIZE],
         }
     }
 
-    pub fn time_update(&mut self) {
-        self.time_data.update();
+    pub(crate) fn time_update(&mut self, tk: &Timekeeper) {
+        self.time_data.update(tk);
     }
 }
 
@@ -0,0 +1,261 @@
+//! Placement policy for the vDSO in user address spaces.
+use memory_addr::{align_down, is_aligned};
+use rand_core::RngCore;
+use rand_pcg::Pcg64Mcg;
+use spin::RwLock;
+
+use crate::PAGE_SIZE;
+
+/// Default lowest address of the vDSO image.
+const DEFAULT_VDSO_BASE: usize = 0x7f00_0000;
+/// Default randomization: 256 pages above the base.
+const DEFAULT_ENTROPY_BITS: u32 = 8;
+/// Upper bound for [`VdsoAslrPolicy::entropy_bits`].
+const MAX_ENTROPY_BITS: u32 = 28;
+
+/// How the vDSO is placed in a user address space.
+///
+/// If the address space reports its mmap base, the `[vvar][vdso]` span is
+/// placed top-down below it, like Linux does. Otherwise it goes into a fixed
+/// window above `base`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VdsoAslrPolicy {
+    /// Lowest page-aligned address the vDSO image may start at when the
+    /// address space does not report an mmap base. The vvar pages are mapped
+    /// right below it.
+    pub base: usize,
+    /// Number of random bits in the page offset of the vDSO.
+    pub entropy_bits: u32,
+    /// Whether to randomize at all. Cleared for
+    /// `personality(ADDR_NO_RANDOMIZE)` and debuggers, which then get the
+    /// vDSO right below the mmap base (or at `base`).
+    pub randomize: bool,
+    /// Fixed address of the vDSO image, for reproducible runs. Overrides the
+    /// other fields.
+    pub fixed: Option<usize>,
+}
+
+impl Default for VdsoAslrPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VdsoAslrPolicy {
+    /// The built-in policy: 8 bits of entropy above `0x7f00_0000`.
+    pub const fn new() -> Self {
+        Self {
+            base: DEFAULT_VDSO_BASE,
+            entropy_bits: DEFAULT_ENTROPY_BITS,
+            randomize: true,
+            fixed: None,
+        }
+    }
+
+    /// Use `base` as the lowest address of the vDSO image.
+    pub const fn with_base(mut self, base: usize) -> Self {
+        assert!(
+            is_aligned(base, PAGE_SIZE),
+            "vDSO base must be page aligned"
+        );
+        self.base = base;
+        self
+    }
+
+    /// Randomize the vDSO over `2^bits` pages.
+    pub const fn with_entropy_bits(mut self, bits: u32) -> Self {
+        assert!(bits <= MAX_ENTROPY_BITS, "too many vDSO entropy bits");
+        self.entropy_bits = bits;
+        self
+    }
+
+    /// Do not randomize the vDSO, e.g. for `ADDR_NO_RANDOMIZE`.
+    pub const fn no_randomize(mut self) -> Self {
+        self.randomize = false;
+        self
+    }
+
+    /// Always place the vDSO image at `addr`.
+    pub const fn fixed(mut self, addr: usize) -> Self {
+        assert!(
+            is_aligned(addr, PAGE_SIZE),
+            "vDSO address must be page aligned"
+        );
+        self.fixed = Some(addr);
+        self
+    }
+
+    /// Whether placements under this policy consume randomness.
+    pub const fn is_randomized(&self) -> bool {
+        self.fixed.is_none() && self.randomize && self.entropy_bits > 0
+    }
+
+    /// Page-aligned end of the `[vvar][vdso]` span for a random value
+    /// `rand`, given the mmap base of the address space (if known) and the
+    /// size of the image mapping.
+    pub fn span_end(&self, rand: u64, mmap_base: Option<usize>, vdso_size: usize) -> usize {
+        if let Some(addr) = self.fixed {
+            return addr + vdso_size;
+        }
+        let page_off = if self.is_randomized() {
+            (rand & ((1u64 << self.entropy_bits) - 1)) as usize
+        } else {
+            0
+        };
+        match mmap_base {
+            Some(top) => align_down(top, PAGE_SIZE).saturating_sub(page_off * PAGE_SIZE),
+            None => self.base + page_off * PAGE_SIZE + vdso_size,
+        }
+    }
+}
+
+static DEFAULT_POLICY: RwLock<VdsoAslrPolicy> = RwLock::new(VdsoAslrPolicy::new());
+
+/// Set the policy used for processes without one of their own, typically once
+/// at boot.
+pub fn set_default_aslr_policy(policy: VdsoAslrPolicy) {
+    *DEFAULT_POLICY.write() = policy;
+}
+
+/// The policy used for processes without one of their own.
+pub fn default_aslr_policy() -> VdsoAslrPolicy {
+    *DEFAULT_POLICY.read()
+}
+
+/// Source of randomness for vDSO placement.
+///
+/// Kernels with their own CRNG implement this for it; [`HwRng`] and
+/// [`AtRandom`] are provided for the common cases.
+pub trait EntropySource {
+    /// Return 64 random bits, or `None` if the source is unavailable.
+    fn random_u64(&mut self) -> Option<u64>;
+}
+
+/// The CPU's random number instruction: `RDRAND` on x86_64, `RNDR` on aarch64.
+///
+/// Unavailable on other architectures or CPUs without it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HwRng;
+
+impl EntropySource for HwRng {
+    fn random_u64(&mut self) -> Option<u64> {
+        hw_random_u64()
+    }
+}
+
+/// Randomness derived from the 16 `AT_RANDOM` bytes of the exec.
+pub struct AtRandom {
+    rng: Pcg64Mcg,
+}
+
+impl AtRandom {
+    /// Create a source from the bytes `AT_RANDOM` points to.
+    pub fn new(bytes: [u8; 16]) -> Self {
+        Self {
+            rng: Pcg64Mcg::new(u128::from_le_bytes(bytes)),
+        }
+    }
+}
+
+impl EntropySource for AtRandom {
+    fn random_u64(&mut self) -> Option<u64> {
+        Some(self.rng.next_u64())
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        fn hw_random_u64() -> Option<u64> {
+            use core::arch::x86_64::{__cpuid, _rdrand64_step};
+
+            // CPUID.01H:ECX.RDRAND[bit 30]
+            #[allow(unused_unsafe)]
+            let ecx = unsafe { __cpuid(1) }.ecx;
+            if ecx & (1 << 30) == 0 {
+                return None;
+            }
+            // RDRAND may transiently fail; retry a few times as Intel recommends.
+            let mut val = 0u64;
+            (0..10)
+                .any(|_| unsafe { _rdrand64_step(&mut val) } == 1)
+                .then_some(val)
+        }
+    } else if #[cfg(target_arch = "aarch64")] {
+        fn hw_random_u64() -> Option<u64> {
+            let isar0: u64;
+            unsafe { core::arch::asm!("mrs {}, ID_AA64ISAR0_EL1", out(reg) isar0) };
+            // ID_AA64ISAR0_EL1.RNDR[63:60]
+            if (isar0 >> 60) & 0xf == 0 {
+                return None;
+            }
+            let val: u64;
+            let ok: u64;
+            unsafe {
+                // RNDR clears PSTATE.Z on success.
+                core::arch::asm!(
+                    "mrs {0}, s3_3_c2_c4_0",
+                    "cset {1}, ne",
+                    out(reg) val,
+                    out(reg) ok,
+                    options(nomem, nostack)
+                );
+            }
+            (ok != 0).then_some(val)
+        }
+    } else {
+        fn hw_random_u64() -> Option<u64> {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VDSO_SIZE: usize = 2 * PAGE_SIZE;
+
+    #[test]
+    fn fixed_overrides_everything() {
+        let policy = VdsoAslrPolicy::new().fixed(0x4000_0000);
+        assert_eq!(
+            policy.span_end(u64::MAX, Some(0x7fff_0000_0000), VDSO_SIZE),
+            0x4000_0000 + VDSO_SIZE
+        );
+        assert!(!policy.is_randomized());
+    }
+
+    #[test]
+    fn top_down_below_mmap_base() {
+        let policy = VdsoAslrPolicy::new().with_entropy_bits(4);
+        let top = 0x7fff_0000_0000 + 123;
+        let base = align_down(top, PAGE_SIZE);
+        assert_eq!(policy.span_end(0, Some(top), VDSO_SIZE), base);
+        // Only the low `entropy_bits` bits of the random value are used.
+        assert_eq!(
+            policy.span_end(0x13, Some(top), VDSO_SIZE),
+            base - 3 * PAGE_SIZE
+        );
+        assert_eq!(
+            policy.no_randomize().span_end(0x13, Some(top), VDSO_SIZE),
+            base
+        );
+        // Never wraps below zero.
+        assert_eq!(policy.span_end(0xf, Some(PAGE_SIZE), VDSO_SIZE), 0);
+    }
+
+    #[test]
+    fn window_above_base() {
+        let policy = VdsoAslrPolicy::new().with_base(0x1000_0000);
+        assert_eq!(
+            policy.span_end(0x105, None, VDSO_SIZE),
+            0x1000_0000 + 5 * PAGE_SIZE + VDSO_SIZE
+        );
+        let policy = policy.with_entropy_bits(0);
+        assert!(!policy.is_randomized());
+        assert_eq!(
+            policy.span_end(0x105, None, VDSO_SIZE),
+            0x1000_0000 + VDSO_SIZE
+        );
+    }
+}
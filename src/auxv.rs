@@ -0,0 +1,119 @@
+//! vDSO-related entries of the auxiliary vector.
+extern crate alloc;
+use alloc::vec::Vec;
+
+use kernel_elf_parser::{AuxEntry, AuxType};
+
+use crate::mapper::VdsoLayout;
+
+/// Set `ty` to `value` in `auxv`, replacing an existing entry.
+///
+/// New entries are inserted before a terminating `AT_NULL`, if any.
+pub fn set_aux(auxv: &mut Vec<AuxEntry>, ty: AuxType, value: usize) {
+    let entry = AuxEntry::new(ty, value);
+    if let Some(existing) = auxv.iter_mut().find(|e| e.get_type() == ty) {
+        *existing = entry;
+    } else if let Some(null) = auxv.iter().position(|e| e.get_type() == AuxType::NULL) {
+        auxv.insert(null, entry);
+    } else {
+        auxv.push(entry);
+    }
+}
+
+/// Builder for the auxv entries libcs consult next to `AT_SYSINFO_EHDR`.
+#[derive(Debug, Clone, Copy)]
+pub struct VdsoAuxv {
+    sysinfo_ehdr: usize,
+    hwcap: usize,
+    hwcap2: usize,
+    minsigstksz: Option<usize>,
+    sysinfo: Option<usize>,
+}
+
+impl VdsoAuxv {
+    /// Entries for a vDSO placed at `layout`, with `AT_HWCAP`/`AT_HWCAP2` of
+    /// the current CPU.
+    pub fn new(layout: &VdsoLayout) -> Self {
+        Self {
+            sysinfo_ehdr: layout.vdso_base,
+            hwcap: crate::hwcap::hwcap(),
+            hwcap2: crate::hwcap::hwcap2(),
+            minsigstksz: None,
+            sysinfo: None,
+        }
+    }
+
+    /// Report `AT_MINSIGSTKSZ` for a kernel whose signal frame takes
+    /// `frame_size` bytes of the user stack.
+    pub fn with_signal_frame_size(mut self, frame_size: usize) -> Self {
+        self.minsigstksz = Some(crate::hwcap::minsigstksz(frame_size));
+        self
+    }
+
+    /// Report `AT_SYSINFO`, the system call entry point of compat tasks.
+    pub fn with_compat_sysinfo(mut self, entry: usize) -> Self {
+        self.sysinfo = Some(entry);
+        self
+    }
+
+    /// Write the entries into `auxv`, replacing existing ones.
+    pub fn apply(&self, auxv: &mut Vec<AuxEntry>) {
+        set_aux(auxv, AuxType::SYSINFO_EHDR, self.sysinfo_ehdr);
+        set_aux(auxv, AuxType::HWCAP, self.hwcap);
+        set_aux(auxv, AuxType::HWCAP2, self.hwcap2);
+        if let Some(minsigstksz) = self.minsigstksz {
+            set_aux(auxv, AuxType::MINSIGSTKSZ, minsigstksz);
+        }
+        if let Some(sysinfo) = self.sysinfo {
+            set_aux(auxv, AuxType::SYSINFO, sysinfo);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(auxv: &[AuxEntry]) -> Vec<(AuxType, usize)> {
+        auxv.iter().map(|e| (e.get_type(), e.value())).collect()
+    }
+
+    #[test]
+    fn set_aux_replaces_existing() {
+        let mut auxv = alloc::vec![
+            AuxEntry::new(AuxType::PAGESZ, 0x1000),
+            AuxEntry::new(AuxType::SYSINFO_EHDR, 1),
+            AuxEntry::new(AuxType::NULL, 0),
+        ];
+        set_aux(&mut auxv, AuxType::SYSINFO_EHDR, 2);
+        assert_eq!(
+            entries(&auxv),
+            [
+                (AuxType::PAGESZ, 0x1000),
+                (AuxType::SYSINFO_EHDR, 2),
+                (AuxType::NULL, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_aux_inserts_before_null() {
+        let mut auxv = alloc::vec![
+            AuxEntry::new(AuxType::PAGESZ, 0x1000),
+            AuxEntry::new(AuxType::NULL, 0),
+        ];
+        set_aux(&mut auxv, AuxType::HWCAP, 3);
+        assert_eq!(
+            entries(&auxv),
+            [
+                (AuxType::PAGESZ, 0x1000),
+                (AuxType::HWCAP, 3),
+                (AuxType::NULL, 0),
+            ]
+        );
+
+        let mut auxv = Vec::new();
+        set_aux(&mut auxv, AuxType::HWCAP, 3);
+        assert_eq!(entries(&auxv), [(AuxType::HWCAP, 3)]);
+    }
+}
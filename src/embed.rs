@@ -5,15 +5,17 @@ macro_rules! include_vdso {
         concat!(
             ".global vdso_start, vdso_end\n",
             ".section .rodata\n",
-            ".balign 4096\n",
+            ".balign {page_size}\n",
             "vdso_start:\n",
             ".incbin \"",
             env!("CARGO_MANIFEST_DIR"),
             "/vdso/vdso_",
             $arch,
             ".so\"\n",
-            ".balign 4096\n",
             "vdso_end:\n",
+            // Keep the rest of the last image page, which user processes can
+            // read, free of kernel data.
+            ".balign {page_size}\n",
             ".previous"
         )
     };
@@ -21,12 +23,12 @@ macro_rules! include_vdso {
 
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
-        global_asm!(include_vdso!("x86_64"));
+        global_asm!(include_vdso!("x86_64"), page_size = const crate::PAGE_SIZE);
     } else if #[cfg(target_arch = "riscv64")] {
-        global_asm!(include_vdso!("riscv64"));
+        global_asm!(include_vdso!("riscv64"), page_size = const crate::PAGE_SIZE);
     } else if #[cfg(target_arch = "aarch64")]{
-        global_asm!(include_vdso!("aarch64"));
+        global_asm!(include_vdso!("aarch64"), page_size = const crate::PAGE_SIZE);
     } else if #[cfg(any(target_arch = "loongarch64"))] {
-        global_asm!(include_vdso!("loongarch64"));
+        global_asm!(include_vdso!("loongarch64"), page_size = const crate::PAGE_SIZE);
     }
 }
@@ -0,0 +1,91 @@
+//! Errors reported by the vDSO loader.
+use core::fmt;
+
+use axerrno::AxError;
+
+use crate::validate::ValidationError;
+
+/// Result type of the vDSO loader.
+pub type VdsoResult<T = ()> = Result<T, VdsoError>;
+
+/// Why the vDSO could not be prepared or installed.
+///
+/// Converts into [`AxError`] for callers that only need an errno.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VdsoError {
+    /// No vDSO image is embedded in the kernel.
+    MissingImage,
+    /// Allocating memory or user address space for the vDSO failed.
+    OutOfMemory,
+    /// The image is not a usable ELF file.
+    MalformedElf(ElfDetail),
+    /// The image cannot be mapped the way its program headers ask for.
+    LayoutMismatch {
+        /// `p_vaddr` of the offending segment.
+        vaddr: usize,
+        /// What is wrong with it.
+        reason: &'static str,
+    },
+    /// A fixed vDSO address collides with existing mappings.
+    AddressInUse(usize),
+    /// A requested vDSO address is misaligned or leaves no room for the vvar
+    /// pages.
+    BadAddress(usize),
+    /// The [`VdsoMapper`](crate::mapper::VdsoMapper) failed to install a
+    /// mapping.
+    MappingFailed(AxError),
+    /// A user `mremap` would move only part of the vDSO or vvar pages, or
+    /// resize them.
+    InvalidMove,
+}
+
+/// What is wrong with a malformed vDSO image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfDetail {
+    /// The image failed [`validate_image`](crate::validate::validate_image).
+    Validation(ValidationError),
+    /// The image passed validation but could not be parsed.
+    Parse(&'static str),
+}
+
+impl fmt::Display for VdsoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingImage => write!(f, "no vDSO image is embedded"),
+            Self::OutOfMemory => write!(f, "out of memory"),
+            Self::MalformedElf(ElfDetail::Validation(e)) => write!(f, "invalid vDSO image: {e}"),
+            Self::MalformedElf(ElfDetail::Parse(e)) => write!(f, "malformed vDSO image: {e}"),
+            Self::LayoutMismatch { vaddr, reason } => {
+                write!(f, "vDSO segment at {vaddr:#x} {reason}")
+            }
+            Self::AddressInUse(addr) => {
+                write!(f, "vDSO address {addr:#x} collides with existing mappings")
+            }
+            Self::BadAddress(addr) => write!(f, "vDSO address {addr:#x} is not usable"),
+            Self::MappingFailed(e) => write!(f, "failed to map the vDSO: {e:?}"),
+            Self::InvalidMove => write!(f, "the vDSO can only be moved as a whole"),
+        }
+    }
+}
+
+impl From<ValidationError> for VdsoError {
+    fn from(e: ValidationError) -> Self {
+        Self::MalformedElf(ElfDetail::Validation(e))
+    }
+}
+
+impl From<VdsoError> for AxError {
+    fn from(e: VdsoError) -> Self {
+        match e {
+            VdsoError::MissingImage => AxError::NotFound,
+            VdsoError::OutOfMemory => AxError::NoMemory,
+            VdsoError::MalformedElf(_) | VdsoError::LayoutMismatch { .. } => {
+                AxError::InvalidExecutable
+            }
+            VdsoError::AddressInUse(_) => AxError::AlreadyExists,
+            VdsoError::BadAddress(_) => AxError::InvalidInput,
+            VdsoError::MappingFailed(e) => e,
+            VdsoError::InvalidMove => AxError::InvalidInput,
+        }
+    }
+}
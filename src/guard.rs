@@ -1,9 +1,16 @@
 extern crate alloc;
 
-use alloc::alloc::dealloc;
+use alloc::{alloc::dealloc, vec::Vec};
 use core::alloc::Layout;
 
-const PAGE_SIZE_4K: usize = 4096;
+use axerrno::AxResult;
+use axplat::mem::PhysAddr;
+use log::warn;
+
+use crate::{
+    PAGE_SIZE,
+    mapper::{VdsoMapper, VdsoRegion, VdsoSegment},
+};
 
 /// RAII guard that will free allocated vdso pages on Drop unless disarmed.
 pub struct VdsoAllocGuard {
@@ -24,10 +31,81 @@ impl Drop for VdsoAllocGuard {
     fn drop(&mut self) {
         if let Some((vaddr, pages)) = self.alloc {
             // free memory allocated with `alloc_zeroed` above
-            let size = pages * PAGE_SIZE_4K;
-            if let Ok(layout) = Layout::from_size_align(size, PAGE_SIZE_4K) {
+            let size = pages * PAGE_SIZE;
+            if let Ok(layout) = Layout::from_size_align(size, PAGE_SIZE) {
                 unsafe { dealloc(vaddr as *mut u8, layout) };
             }
         }
     }
 }
+
+/// Mapper wrapper that records every range it maps and unmaps them all on Drop
+/// unless disarmed, so that a vDSO is installed either completely or not at
+/// all.
+pub struct VdsoMapGuard<'a, M: VdsoMapper + ?Sized> {
+    mapper: &'a mut M,
+    mapped: Vec<(usize, usize)>,
+}
+
+impl<'a, M: VdsoMapper + ?Sized> VdsoMapGuard<'a, M> {
+    pub fn new(mapper: &'a mut M) -> Self {
+        Self {
+            mapper,
+            mapped: Vec::new(),
+        }
+    }
+
+    pub fn disarm(&mut self) {
+        self.mapped.clear();
+    }
+}
+
+impl<M: VdsoMapper + ?Sized> VdsoMapper for VdsoMapGuard<'_, M> {
+    fn map_segment(&mut self, segment: &VdsoSegment) -> AxResult<()> {
+        self.mapper.map_segment(segment)?;
+        self.mapped.push((segment.user_start, segment.size));
+        Ok(())
+    }
+
+    fn map_vvar(
+        &mut self,
+        region: VdsoRegion,
+        user_start: usize,
+        paddr: PhysAddr,
+        size: usize,
+    ) -> AxResult<()> {
+        self.mapper.map_vvar(region, user_start, paddr, size)?;
+        self.mapped.push((user_start, size));
+        Ok(())
+    }
+
+    fn unmap(&mut self, user_start: usize, size: usize) -> AxResult<()> {
+        self.mapper.unmap(user_start, size)?;
+        self.mapped
+            .retain(|&(start, len)| (start, len) != (user_start, size));
+        Ok(())
+    }
+
+    fn mmap_base(&self) -> Option<usize> {
+        self.mapper.mmap_base()
+    }
+
+    fn find_free_range(&self, end: usize, size: usize) -> Option<usize> {
+        self.mapper.find_free_range(end, size)
+    }
+}
+
+impl<M: VdsoMapper + ?Sized> Drop for VdsoMapGuard<'_, M> {
+    fn drop(&mut self) {
+        // Undo in reverse order; keep going so that as much as possible is
+        // rolled back.
+        while let Some((start, size)) = self.mapped.pop() {
+            if let Err(e) = self.mapper.unmap(start, size) {
+                warn!(
+                    "failed to roll back vDSO mapping at {start:#x}..{:#x}: {e:?}",
+                    start + size
+                );
+            }
+        }
+    }
+}
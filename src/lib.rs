@@ -1,6 +1,34 @@
 #![no_std]
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "page-size-64k")] {
+        /// Base page size of the kernel, selected by the `page-size-*` features.
+        pub const PAGE_SIZE: usize = 0x10000;
+    } else if #[cfg(feature = "page-size-16k")] {
+        /// Base page size of the kernel, selected by the `page-size-*` features.
+        pub const PAGE_SIZE: usize = 0x4000;
+    } else {
+        /// Base page size of the kernel, selected by the `page-size-*` features.
+        pub const PAGE_SIZE: usize = 0x1000;
+    }
+}
+
+#[cfg(all(
+    target_arch = "x86_64",
+    any(feature = "page-size-16k", feature = "page-size-64k")
+))]
+compile_error!("x86_64 only supports 4K pages");
+
+pub mod aslr;
+pub mod auxv;
 pub mod embed;
+pub mod error;
 pub mod guard;
+pub mod mapper;
+pub mod mapping;
+pub mod ntp;
+pub mod timekeeping;
+pub mod validate;
 pub mod vdso;
 mod vdso_time_data;
 
@@ -19,3 +47,10 @@ cfg_if::cfg_if! {
         pub use self::loongarch64::*;
     }
 }
+
+// The riscv64 `riscv,isa` parser is plain string handling; test it on the host
+// too.
+#[cfg(all(test, not(target_arch = "riscv64")))]
+#[allow(dead_code)]
+#[path = "riscv64/hwcap.rs"]
+mod riscv64_hwcap;
@@ -1,5 +1,4 @@
-pub const VVAR_PAGES: usize = 20;
-pub const SIGRETURN_SYM_OFFSET: usize = 0xee8;
+include!(concat!(env!("OUT_DIR"), "/vdso_config.rs"));
 
 #[repr(i32)]
 pub enum ClockMode {
@@ -0,0 +1,59 @@
+const HWCAP_LOONGARCH_CPUCFG: usize = 1 << 0;
+const HWCAP_LOONGARCH_LAM: usize = 1 << 1;
+const HWCAP_LOONGARCH_UAL: usize = 1 << 2;
+const HWCAP_LOONGARCH_FPU: usize = 1 << 3;
+const HWCAP_LOONGARCH_LSX: usize = 1 << 4;
+const HWCAP_LOONGARCH_LASX: usize = 1 << 5;
+const HWCAP_LOONGARCH_CRC32: usize = 1 << 6;
+const HWCAP_LOONGARCH_COMPLEX: usize = 1 << 7;
+const HWCAP_LOONGARCH_CRYPTO: usize = 1 << 8;
+
+const CPUCFG1_UAL: usize = 1 << 20;
+const CPUCFG1_CRC32: usize = 1 << 25;
+const CPUCFG2_FP: usize = 1 << 0;
+const CPUCFG2_LSX: usize = 1 << 6;
+const CPUCFG2_LASX: usize = 1 << 7;
+const CPUCFG2_COMPLEX: usize = 1 << 8;
+const CPUCFG2_CRYPTO: usize = 1 << 9;
+const CPUCFG2_LAM: usize = 1 << 22;
+
+fn cpucfg(word: usize) -> usize {
+    let val: usize;
+    unsafe {
+        core::arch::asm!("cpucfg {}, {}", out(reg) val, in(reg) word, options(nomem, nostack))
+    };
+    val
+}
+
+/// `AT_HWCAP`: the Linux `HWCAP_LOONGARCH_*` bits derived from `cpucfg`.
+pub fn hwcap() -> usize {
+    let cfg1 = cpucfg(1);
+    let cfg2 = cpucfg(2);
+    let mut caps = HWCAP_LOONGARCH_CPUCFG;
+
+    for (cfg, bit, cap) in [
+        (cfg1, CPUCFG1_UAL, HWCAP_LOONGARCH_UAL),
+        (cfg1, CPUCFG1_CRC32, HWCAP_LOONGARCH_CRC32),
+        (cfg2, CPUCFG2_FP, HWCAP_LOONGARCH_FPU),
+        (cfg2, CPUCFG2_LSX, HWCAP_LOONGARCH_LSX),
+        (cfg2, CPUCFG2_LASX, HWCAP_LOONGARCH_LASX),
+        (cfg2, CPUCFG2_COMPLEX, HWCAP_LOONGARCH_COMPLEX),
+        (cfg2, CPUCFG2_CRYPTO, HWCAP_LOONGARCH_CRYPTO),
+        (cfg2, CPUCFG2_LAM, HWCAP_LOONGARCH_LAM),
+    ] {
+        if cfg & bit != 0 {
+            caps |= cap;
+        }
+    }
+    caps
+}
+
+/// `AT_HWCAP2`: unused on loongarch64.
+pub fn hwcap2() -> usize {
+    0
+}
+
+/// `AT_MINSIGSTKSZ` for a signal frame of `frame_size` bytes.
+pub fn minsigstksz(frame_size: usize) -> usize {
+    frame_size.next_multiple_of(16)
+}
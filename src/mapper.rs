@@ -0,0 +1,128 @@
+//! Interface between the vDSO loader and a user address space.
+use axerrno::AxResult;
+use axplat::mem::PhysAddr;
+use xmas_elf::program::Flags;
+
+/// Maps the pieces of the vDSO into a user address space.
+///
+/// The loader decides where everything goes; implementors only have to
+/// install the requested mappings. All ranges are page aligned.
+pub trait VdsoMapper {
+    /// Map a `PT_LOAD` segment of the vDSO image, or the zero-filled pages
+    /// backing its tail past `p_filesz`, with `segment.perms`, as part of
+    /// `segment.region`.
+    fn map_segment(&mut self, segment: &VdsoSegment) -> AxResult<()>;
+
+    /// Map part of the vvar pages holding `VDSO_DATA`: `size` bytes at `paddr`
+    /// to `user_start`, read-only.
+    ///
+    /// The vvar area may be split into several mappings; `region` says which
+    /// one this is.
+    fn map_vvar(
+        &mut self,
+        region: VdsoRegion,
+        user_start: usize,
+        paddr: PhysAddr,
+        size: usize,
+    ) -> AxResult<()>;
+
+    /// Remove the mapping of `[user_start, user_start + size)` installed by
+    /// [`map_segment`](Self::map_segment) or [`map_vvar`](Self::map_vvar).
+    ///
+    /// Used to roll back a partially installed vDSO. The physical pages are
+    /// shared and must not be freed.
+    fn unmap(&mut self, user_start: usize, size: usize) -> AxResult<()>;
+
+    /// Highest address the vDSO may end at, typically the mmap base of the
+    /// process (or its stack top with a legacy layout).
+    ///
+    /// `None` places the vDSO in the fixed window of the
+    /// [`VdsoAslrPolicy`](crate::aslr::VdsoAslrPolicy).
+    fn mmap_base(&self) -> Option<usize> {
+        None
+    }
+
+    /// Find a free range of `size` bytes that ends at or below `end`,
+    /// searching top-down, and return its start.
+    ///
+    /// `size` covers both the vvar pages and the image. The loader also uses
+    /// this to check that a fixed range is free, so it must reflect the
+    /// mappings of the address space.
+    fn find_free_range(&self, end: usize, size: usize) -> Option<usize>;
+}
+
+/// Kind of a vDSO-related mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VdsoRegion {
+    /// vvar pages holding the time data.
+    Vvar,
+    /// vvar pages holding paravirtual clock data, e.g. the x86 pvclock page.
+    VvarVclock,
+    /// The vDSO image, mapped through
+    /// [`VdsoMapper::map_segment`](VdsoMapper::map_segment).
+    Vdso,
+}
+
+impl VdsoRegion {
+    /// Name of the region as shown in `/proc/<pid>/maps`.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Vvar => "[vvar]",
+            Self::VvarVclock => "[vvar_vclock]",
+            Self::Vdso => "[vdso]",
+        }
+    }
+}
+
+/// User access permissions of a vDSO segment. vDSO segments are never
+/// writable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VdsoPerms {
+    /// Readable.
+    pub read: bool,
+    /// Executable.
+    pub exec: bool,
+}
+
+impl VdsoPerms {
+    /// Permissions for program header flags `flags`, or `None` if they ask
+    /// for a writable mapping.
+    pub fn from_flags(flags: Flags) -> Option<Self> {
+        if flags.is_write() {
+            return None;
+        }
+        Some(Self {
+            read: flags.is_read(),
+            exec: flags.is_execute(),
+        })
+    }
+}
+
+/// A `PT_LOAD` segment of the vDSO image, or part of one, ready to be mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VdsoSegment {
+    /// Page-aligned user address of the segment.
+    pub user_start: usize,
+    /// Physical address of the first page of the segment.
+    pub paddr: PhysAddr,
+    /// Page-rounded size of the segment.
+    pub size: usize,
+    /// Permissions to map the segment with.
+    pub perms: VdsoPerms,
+    /// Region the segment belongs to, always [`VdsoRegion::Vdso`].
+    pub region: VdsoRegion,
+}
+
+/// Where the vDSO and its vvar pages were placed in a user address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VdsoLayout {
+    /// User address of the vDSO ELF header, i.e. the `AT_SYSINFO_EHDR` value.
+    pub vdso_base: usize,
+    /// First user address of the vvar pages, right below the image.
+    pub vvar_start: usize,
+    /// Size of the vvar area in bytes.
+    pub vvar_size: usize,
+    /// Size of the page-rounded image mapping starting at the page of
+    /// `vdso_base`, right above the vvar pages.
+    pub vdso_size: usize,
+}
@@ -0,0 +1,234 @@
+//! Record of a vDSO installed in one user address space.
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use log::{info, warn};
+use memory_addr::MemoryAddr;
+
+use crate::{
+    PAGE_SIZE,
+    error::{VdsoError, VdsoResult},
+    guard::VdsoMapGuard,
+    mapper::{VdsoLayout, VdsoMapper, VdsoRegion, VdsoSegment},
+    vdso::{VdsoImage, get_trampoline_addr, map_vvar_regions, vvar_regions},
+};
+
+/// Where the vDSO of an address space lives, as returned by
+/// [`load_vdso_data`](crate::vdso::load_vdso_data).
+///
+/// Meant to be kept with the process, for signal frame setup,
+/// `/proc/<pid>/maps` and ptrace queries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VdsoMapping {
+    layout: VdsoLayout,
+    segments: Vec<VdsoSegment>,
+    trampoline: Option<usize>,
+    vvar_mapped: bool,
+    vdso_mapped: bool,
+}
+
+impl VdsoMapping {
+    pub(crate) fn new(layout: VdsoLayout, segments: Vec<VdsoSegment>) -> Self {
+        Self {
+            layout,
+            segments,
+            trampoline: get_trampoline_addr(layout.vdso_base),
+            vvar_mapped: true,
+            vdso_mapped: true,
+        }
+    }
+
+    /// Placement of the vDSO and its vvar pages.
+    pub fn layout(&self) -> &VdsoLayout {
+        &self.layout
+    }
+
+    /// User address of the vDSO ELF header, i.e. the `AT_SYSINFO_EHDR` value.
+    pub fn vdso_base(&self) -> usize {
+        self.layout.vdso_base
+    }
+
+    /// User range of the vvar pages.
+    pub fn vvar_range(&self) -> Range<usize> {
+        self.layout.vvar_start..self.layout.vvar_start + self.layout.vvar_size
+    }
+
+    /// User range of the page-rounded image mapping.
+    pub fn vdso_range(&self) -> Range<usize> {
+        let start = self.layout.vdso_base.align_down(PAGE_SIZE);
+        start..start + self.layout.vdso_size
+    }
+
+    /// Whether the image is still mapped, i.e. has not been unmapped by the
+    /// process.
+    pub fn is_vdso_mapped(&self) -> bool {
+        self.vdso_mapped
+    }
+
+    /// Whether the vvar pages are still mapped.
+    pub fn is_vvar_mapped(&self) -> bool {
+        self.vvar_mapped
+    }
+
+    /// The mappings installed for the image, in the order they were mapped.
+    pub fn segments(&self) -> &[VdsoSegment] {
+        &self.segments
+    }
+
+    /// User address of the signal return trampoline, if the vDSO exports one
+    /// and is still mapped.
+    ///
+    /// When this is `None`, signal delivery has to rely on `SA_RESTORER`.
+    pub fn trampoline(&self) -> Option<usize> {
+        self.trampoline
+    }
+
+    /// User address of a vDSO symbol given as `name` or `name@VERSION`.
+    pub fn symbol(&self, spec: &str) -> Option<usize> {
+        if !self.vdso_mapped {
+            return None;
+        }
+        let symbol = VdsoImage::embedded()?.resolve(spec)?;
+        Some(self.layout.vdso_base + symbol.offset)
+    }
+
+    /// The regions still mapped, in address order, e.g. for
+    /// `/proc/<pid>/maps`.
+    ///
+    /// The vvar area may show up as several regions. A region moved by the
+    /// process is reported at its new place.
+    pub fn regions(&self) -> impl Iterator<Item = (VdsoRegion, Range<usize>)> {
+        let vvar_start = self.layout.vvar_start;
+        let mut regions: Vec<_> = vvar_regions()
+            .filter(|_| self.vvar_mapped)
+            .map(|(region, range)| (region, vvar_start + range.start..vvar_start + range.end))
+            .chain(
+                self.vdso_mapped
+                    .then(|| (VdsoRegion::Vdso, self.vdso_range())),
+            )
+            .collect();
+        regions.sort_by_key(|(_, range)| range.start);
+        regions.into_iter()
+    }
+
+    /// Whether `addr` lies in the vDSO or its vvar pages.
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.vvar_mapped && self.vvar_range().contains(&addr))
+            || (self.vdso_mapped && self.vdso_range().contains(&addr))
+    }
+
+    /// Check a user `mremap` of `old` to `new` and, if it moves the vDSO or
+    /// the vvar pages, record their new place.
+    ///
+    /// Call before performing the move. `[vdso]` and `[vvar]` are separate
+    /// mappings and are moved one at a time, as CRIU does on restore, so each
+    /// may be moved on its own. Moves must be of a whole region to a
+    /// page-aligned address of the same size; anything else touching them
+    /// fails with [`VdsoError::InvalidMove`].
+    ///
+    /// A `new` range landing on a region (`MREMAP_FIXED`) replaces it as
+    /// [`on_munmap`](Self::on_munmap) would.
+    pub fn on_mremap(&mut self, old: Range<usize>, new: Range<usize>) -> VdsoResult {
+        let vvar = self.vvar_range();
+        let vdso = self.vdso_range();
+        let moves_vvar = self.vvar_mapped && overlaps(&old, &vvar);
+        let moves_vdso = self.vdso_mapped && overlaps(&old, &vdso);
+        if !moves_vvar && !moves_vdso {
+            self.on_munmap(new);
+            return Ok(());
+        }
+        let whole = if moves_vvar {
+            !moves_vdso && old == vvar
+        } else {
+            old == vdso
+        };
+        if !whole || new.len() != old.len() || !new.start.is_aligned(PAGE_SIZE) {
+            warn!(
+                "refusing mremap of {:#x}..{:#x} to {:#x}..{:#x}: not a whole [vdso] or [vvar]",
+                old.start, old.end, new.start, new.end
+            );
+            return Err(VdsoError::InvalidMove);
+        }
+        // The kernel refuses overlapping moves, so `new` can only land on the
+        // region not being moved.
+        if !overlaps(&old, &new) {
+            self.on_munmap(new.clone());
+        }
+
+        let delta = new.start.wrapping_sub(old.start);
+        if moves_vvar {
+            self.layout.vvar_start = self.layout.vvar_start.wrapping_add(delta);
+        }
+        if moves_vdso {
+            self.layout.vdso_base = self.layout.vdso_base.wrapping_add(delta);
+            for segment in &mut self.segments {
+                segment.user_start = segment.user_start.wrapping_add(delta);
+            }
+            self.trampoline = get_trampoline_addr(self.layout.vdso_base);
+        }
+        info!(
+            "vDSO region {:#x}..{:#x} moved to {:#x}",
+            old.start, old.end, new.start
+        );
+        Ok(())
+    }
+
+    /// Record a user `munmap` of `range`.
+    ///
+    /// Unmapping any part of the image marks the vDSO as gone: there is no
+    /// trampoline any more and symbols no longer resolve. Unmapping any part
+    /// of the vvar pages marks them as gone.
+    pub fn on_munmap(&mut self, range: Range<usize>) {
+        if self.vdso_mapped && overlaps(&range, &self.vdso_range()) {
+            info!("[vdso] at {:#x} unmapped", self.layout.vdso_base);
+            self.vdso_mapped = false;
+            self.trampoline = None;
+        }
+        if self.vvar_mapped && overlaps(&range, &self.vvar_range()) {
+            info!("[vvar] at {:#x} unmapped", self.layout.vvar_start);
+            self.vvar_mapped = false;
+        }
+    }
+
+    /// Reproduce this vDSO in the address space behind `mapper`, e.g. the
+    /// child's on fork.
+    ///
+    /// The vvar pages and every segment still mapped are mapped at the same
+    /// user addresses and onto the same shared physical pages, so the ASLR
+    /// slot and the trampoline address carry over. As with the initial load,
+    /// nothing stays mapped if any step fails.
+    pub fn clone_into<M>(&self, mapper: &mut M) -> VdsoResult<Self>
+    where
+        M: VdsoMapper + ?Sized,
+    {
+        let vvar = self.vvar_range();
+        let vdso = self.vdso_range();
+        for (mapped, range) in [(self.vvar_mapped, &vvar), (self.vdso_mapped, &vdso)] {
+            if mapped && mapper.find_free_range(range.end, range.len()) != Some(range.start) {
+                let e = VdsoError::AddressInUse(range.start);
+                warn!("cannot clone vDSO: {e}");
+                return Err(e);
+            }
+        }
+
+        let mut guard = VdsoMapGuard::new(mapper);
+        if self.vvar_mapped {
+            map_vvar_regions(vvar.start, &mut guard)?;
+        }
+        if self.vdso_mapped {
+            for segment in &self.segments {
+                guard
+                    .map_segment(segment)
+                    .map_err(VdsoError::MappingFailed)?;
+            }
+        }
+        guard.disarm();
+        Ok(self.clone())
+    }
+}
+
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
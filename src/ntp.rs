@@ -0,0 +1,842 @@
+//! NTP clock discipline behind `adjtimex(2)` and `clock_adjtime(2)`.
+//!
+//! Follows the kernel PLL/FLL of Linux (`kernel/time/ntp.c`), with one NTP
+//! interval per second. The resulting length of the current second slews
+//! `CLOCK_MONOTONIC` and the clocks derived from it through the `mult` the
+//! vDSO reads, while `CLOCK_MONOTONIC_RAW` is left alone. The kernel follows
+//! the slewed clock through
+//! [`monotonic_nanos`](crate::timekeeping::monotonic_nanos).
+//!
+//! Leap seconds announced with `STA_INS` or `STA_DEL` are scheduled for the
+//! next midnight UTC and applied by the first update at or past it, stepping
+//! `CLOCK_REALTIME`, or smeared over a window by slewing it alone, see
+//! [`set_leap_smear`]. The monotonic clocks and `CLOCK_TAI` count on
+//! undisturbed either way.
+use axerrno::{AxError, AxResult};
+use axplat::time::{NANOS_PER_SEC, current_ticks};
+use log::info;
+
+use crate::{timekeeping::TIMEKEEPER, vdso::publish_vdso_data};
+
+/// `modes` bits of [`Timex`].
+pub const ADJ_OFFSET: u32 = 0x0001;
+pub const ADJ_FREQUENCY: u32 = 0x0002;
+pub const ADJ_MAXERROR: u32 = 0x0004;
+pub const ADJ_ESTERROR: u32 = 0x0008;
+pub const ADJ_STATUS: u32 = 0x0010;
+pub const ADJ_TIMECONST: u32 = 0x0020;
+pub const ADJ_TAI: u32 = 0x0080;
+pub const ADJ_SETOFFSET: u32 = 0x0100;
+pub const ADJ_MICRO: u32 = 0x1000;
+pub const ADJ_NANO: u32 = 0x2000;
+pub const ADJ_TICK: u32 = 0x4000;
+pub const ADJ_OFFSET_SINGLESHOT: u32 = 0x8001;
+pub const ADJ_OFFSET_SS_READ: u32 = 0xa001;
+const ADJ_ADJTIME: u32 = 0x8000;
+const ADJ_OFFSET_READONLY: u32 = 0x2000;
+
+/// `status` bits of [`Timex`].
+pub const STA_PLL: i32 = 0x0001;
+pub const STA_PPSFREQ: i32 = 0x0002;
+pub const STA_PPSTIME: i32 = 0x0004;
+pub const STA_FLL: i32 = 0x0008;
+pub const STA_INS: i32 = 0x0010;
+pub const STA_DEL: i32 = 0x0020;
+pub const STA_UNSYNC: i32 = 0x0040;
+pub const STA_FREQHOLD: i32 = 0x0080;
+pub const STA_PPSSIGNAL: i32 = 0x0100;
+pub const STA_PPSJITTER: i32 = 0x0200;
+pub const STA_PPSWANDER: i32 = 0x0400;
+pub const STA_PPSERROR: i32 = 0x0800;
+pub const STA_CLOCKERR: i32 = 0x1000;
+pub const STA_NANO: i32 = 0x2000;
+pub const STA_MODE: i32 = 0x4000;
+pub const STA_CLK: i32 = 0x8000;
+/// Status bits `ADJ_STATUS` cannot change.
+const STA_RONLY: i32 = STA_PPSSIGNAL
+    | STA_PPSJITTER
+    | STA_PPSWANDER
+    | STA_PPSERROR
+    | STA_CLOCKERR
+    | STA_NANO
+    | STA_MODE
+    | STA_CLK;
+
+const NSEC_PER_USEC: i64 = 1000;
+const USER_HZ: i64 = 100;
+/// Fractional bits of scaled nanoseconds.
+pub(crate) const NTP_SCALE_SHIFT: u32 = 32;
+/// Fractional bits of the ppm values in [`Timex`].
+const SHIFT_USEC: u32 = 16;
+/// Scaled nanoseconds per second for a `freq` of one.
+const PPM_SCALE: i64 = NSEC_PER_USEC << (NTP_SCALE_SHIFT - SHIFT_USEC);
+const SHIFT_PLL: u32 = 2;
+const SHIFT_FLL: u32 = 2;
+const MAXTC: i64 = 10;
+/// Shortest update interval for the FLL, in seconds.
+const MINSEC: u64 = 256;
+/// Longest update interval for the PLL, in seconds.
+const MAXSEC: u64 = 2048;
+/// Largest offset taken in, in nanoseconds.
+const MAXPHASE: i64 = 500_000_000;
+/// Largest frequency correction, in nanoseconds per second.
+const MAXFREQ: i64 = 500_000;
+const MAXFREQ_SCALED: i64 = MAXFREQ << NTP_SCALE_SHIFT;
+/// Error beyond which the clock counts as unsynchronized, in microseconds.
+const NTP_PHASE_LIMIT: i64 = (MAXPHASE / NSEC_PER_USEC) << 5;
+/// Largest `adjtime` slew, in microseconds per second.
+const MAX_TICKADJ: i64 = 500;
+const SECS_PER_DAY: u64 = 86400;
+
+/// `struct timex` as passed to `adjtimex(2)`.
+///
+/// The layout matches the Linux ABI of 64-bit targets, so the syscall can copy
+/// it from and to user memory as is.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timex {
+    pub modes: u32,
+    pub offset: i64,
+    pub freq: i64,
+    pub maxerror: i64,
+    pub esterror: i64,
+    pub status: i32,
+    pub constant: i64,
+    pub precision: i64,
+    pub tolerance: i64,
+    pub time_sec: i64,
+    /// Microseconds, or nanoseconds with `STA_NANO`.
+    pub time_usec: i64,
+    pub tick: i64,
+    pub ppsfreq: i64,
+    pub jitter: i64,
+    pub shift: i32,
+    pub stabil: i64,
+    pub jitcnt: i64,
+    pub calcnt: i64,
+    pub errcnt: i64,
+    pub stbcnt: i64,
+    pub tai: i32,
+    pub _reserved: [i32; 11],
+}
+
+/// Clock state returned by [`adjtimex`].
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeState {
+    /// Clock synchronized.
+    Ok    = 0,
+    /// A leap second is to be inserted.
+    Ins   = 1,
+    /// A leap second is to be deleted.
+    Del   = 2,
+    /// A leap second is in progress.
+    Oop   = 3,
+    /// A leap second has occurred.
+    Wait  = 4,
+    /// Clock not synchronized.
+    Error = 5,
+}
+
+/// Clock discipline state.
+pub(crate) struct NtpState {
+    /// Nominal length of a second, in scaled nanoseconds, before corrections.
+    tick_length_base: i64,
+    /// Length of the current second, in scaled nanoseconds.
+    tick_length: i64,
+    /// `tick` of [`Timex`], in microseconds per `USER_HZ`.
+    tick_usec: i64,
+    time_state: TimeState,
+    time_status: i32,
+    /// Offset still to be slewed, in scaled nanoseconds.
+    time_offset: i64,
+    time_constant: i64,
+    time_maxerror: i64,
+    time_esterror: i64,
+    /// Frequency correction, in scaled nanoseconds per second.
+    time_freq: i64,
+    /// Second of the last offset update.
+    time_reftime: u64,
+    /// Offset still to be slewed for `adjtime(3)`, in microseconds.
+    time_adjust: i64,
+    /// TAI minus UTC, in seconds.
+    pub tai: i32,
+    /// Second of `CLOCK_REALTIME` at which the pending leap second is due.
+    next_leap_sec: u64,
+    /// Window leap seconds are smeared over, in seconds, or zero to step.
+    pub leap_smear: u32,
+    /// Share of the leap second being smeared due every second, in scaled
+    /// nanoseconds.
+    smear_rate: i64,
+    /// Part of the leap second still to be smeared, in scaled nanoseconds.
+    smear_left: i64,
+    /// Part of the leap second `CLOCK_REALTIME` has taken in so far, in scaled
+    /// nanoseconds.
+    smear_done: i64,
+}
+
+impl NtpState {
+    pub const fn new() -> Self {
+        let tick_usec = 1_000_000 / USER_HZ;
+        let tick_length = (tick_usec * NSEC_PER_USEC * USER_HZ) << NTP_SCALE_SHIFT;
+        Self {
+            tick_length_base: tick_length,
+            tick_length,
+            tick_usec,
+            time_state: TimeState::Ok,
+            time_status: STA_UNSYNC,
+            time_offset: 0,
+            time_constant: 2,
+            time_maxerror: NTP_PHASE_LIMIT,
+            time_esterror: NTP_PHASE_LIMIT,
+            time_freq: 0,
+            time_reftime: 0,
+            time_adjust: 0,
+            tai: 0,
+            next_leap_sec: u64::MAX,
+            leap_smear: 0,
+            smear_rate: 0,
+            smear_left: 0,
+            smear_done: 0,
+        }
+    }
+
+    /// Length of the current second, in scaled nanoseconds.
+    pub fn tick_length(&self) -> i64 {
+        self.tick_length
+    }
+
+    /// Rate at which the leap second being smeared moves `CLOCK_REALTIME`, in
+    /// scaled nanoseconds per second, or zero.
+    pub fn smear_rate(&self) -> i64 {
+        if self.smear_left == 0 {
+            0
+        } else {
+            self.smear_rate
+        }
+    }
+
+    /// Part of a nanosecond of the smear taken in beyond the whole nanoseconds
+    /// [`accrue`](Self::accrue) returned, in scaled nanoseconds.
+    pub fn smear_frac(&self) -> u64 {
+        (self.smear_done & ((1 << NTP_SCALE_SHIFT) - 1)) as u64
+    }
+
+    /// Start a new second: take the next chunk of the pending offsets into
+    /// its length.
+    pub fn second_overflow(&mut self) {
+        self.time_maxerror += MAXFREQ / NSEC_PER_USEC;
+        if self.time_maxerror > NTP_PHASE_LIMIT {
+            self.time_maxerror = NTP_PHASE_LIMIT;
+            self.time_status |= STA_UNSYNC;
+        }
+
+        self.tick_length = self.tick_length_base;
+        let delta = shift_right(self.time_offset, SHIFT_PLL + self.time_constant as u32);
+        self.time_offset -= delta;
+        self.tick_length += delta;
+
+        let adjust = self.time_adjust.clamp(-MAX_TICKADJ, MAX_TICKADJ);
+        self.time_adjust -= adjust;
+        self.tick_length += (adjust * NSEC_PER_USEC) << NTP_SCALE_SHIFT;
+    }
+
+    /// Smear the part of the pending leap second due over `elapsed`
+    /// nanoseconds of `CLOCK_MONOTONIC`, shifted left by `shift`, and return
+    /// the nanoseconds to move `CLOCK_REALTIME` by for it.
+    ///
+    /// The part due is rounded towards zero, so that `CLOCK_REALTIME` never
+    /// falls behind the rate [`smear_rate`](Self::smear_rate) gives it.
+    /// [`tai_offset_ns`](Self::tai_offset_ns) makes up for the smear, so that
+    /// `CLOCK_TAI` keeps its rate.
+    pub fn accrue(&mut self, elapsed: u128, shift: u32) -> i64 {
+        if self.smear_left == 0 {
+            return 0;
+        }
+        let due =
+            (self.smear_rate as i128 * elapsed as i128 / ((NANOS_PER_SEC as i128) << shift)) as i64;
+        let step = if due.abs() < self.smear_left.abs() {
+            due
+        } else {
+            self.smear_left
+        };
+        self.smear_left -= step;
+        let before = self.smear_done >> NTP_SCALE_SHIFT;
+        self.smear_done += step;
+        let ns = (self.smear_done >> NTP_SCALE_SHIFT) - before;
+
+        if self.smear_left == 0 {
+            // The TAI offset is a whole second off now.
+            self.tai -= self.smear_done.signum() as i32;
+            self.smear_done = 0;
+            self.time_state = TimeState::Wait;
+            info!("Clock: leap second smear complete");
+        }
+        ns
+    }
+
+    /// Run the leap second state machine as `CLOCK_REALTIME` reaches second
+    /// `secs`.
+    ///
+    /// Returns the seconds to step `CLOCK_REALTIME` by, which the TAI offset
+    /// already accounts for.
+    pub fn leap_second(&mut self, secs: u64) -> i64 {
+        let leap = match self.time_state {
+            TimeState::Ok => return 0,
+            TimeState::Ins => -1,
+            TimeState::Del => 1,
+            TimeState::Oop => {
+                if self.smear_left == 0 {
+                    self.time_state = TimeState::Wait;
+                }
+                return 0;
+            }
+            TimeState::Wait | TimeState::Error => {
+                if self.time_status & (STA_INS | STA_DEL) == 0 {
+                    self.time_state = TimeState::Ok;
+                }
+                return 0;
+            }
+        };
+
+        if self.leap_smear != 0 {
+            // Centre the window on the leap second.
+            if secs.saturating_add(self.leap_smear as u64 / 2) >= self.next_leap_sec {
+                self.smear_left = leap * ((NANOS_PER_SEC as i64) << NTP_SCALE_SHIFT);
+                self.smear_rate = self.smear_left / self.leap_smear as i64;
+                self.time_state = TimeState::Oop;
+                self.next_leap_sec = u64::MAX;
+                info!("Clock: smearing leap second over {}s", self.leap_smear);
+            }
+            return 0;
+        }
+        // An inserted second repeats 23:59:59 once midnight is reached, a
+        // deleted one skips from 23:59:59 to midnight. Updates may be late, or
+        // the clock stepped past midnight meanwhile, so apply it then.
+        if secs + ((leap > 0) as u64) < self.next_leap_sec {
+            return 0;
+        }
+        if leap < 0 {
+            self.time_state = TimeState::Oop;
+            info!("Clock: inserting leap second 23:59:60 UTC");
+        } else {
+            self.time_state = TimeState::Wait;
+            info!("Clock: deleting leap second 23:59:59 UTC");
+        }
+        self.next_leap_sec = u64::MAX;
+        self.tai -= leap as i32;
+        leap
+    }
+
+    /// `CLOCK_TAI` minus `CLOCK_REALTIME`, in nanoseconds.
+    pub fn tai_offset_ns(&self) -> i64 {
+        self.tai as i64 * NANOS_PER_SEC as i64 - (self.smear_done >> NTP_SCALE_SHIFT)
+    }
+
+    fn update_frequency(&mut self) {
+        let second_length = (self.tick_usec * NSEC_PER_USEC * USER_HZ) << NTP_SCALE_SHIFT;
+        let new_base = second_length + self.time_freq;
+        self.tick_length += new_base - self.tick_length_base;
+        self.tick_length_base = new_base;
+    }
+
+    /// Take in a measured `offset` at second `now`.
+    fn update_offset(&mut self, offset: i64, now: u64) {
+        if self.time_status & STA_PLL == 0 {
+            return;
+        }
+        let offset = if self.time_status & STA_NANO == 0 {
+            offset.clamp(-1_000_000, 1_000_000) * NSEC_PER_USEC
+        } else {
+            offset
+        }
+        .clamp(-MAXPHASE, MAXPHASE);
+
+        let secs = if self.time_status & STA_FREQHOLD != 0 {
+            0
+        } else {
+            now.saturating_sub(self.time_reftime)
+        };
+        self.time_reftime = now;
+
+        // Long intervals, or STA_FLL, use the FLL: the frequency follows the
+        // offset divided by the interval.
+        self.time_status &= !STA_MODE;
+        let mut freq_adj = 0i128;
+        if secs >= MINSEC && (self.time_status & STA_FLL != 0 || secs > MAXSEC) {
+            self.time_status |= STA_MODE;
+            freq_adj = ((offset as i128) << (NTP_SCALE_SHIFT - SHIFT_FLL)) / secs as i128;
+        }
+        // PLL: the frequency follows the integrated offset. Clamp the interval
+        // so that sparse updates do not drive up its gain.
+        let secs = secs.min(1 << (SHIFT_PLL + 1 + self.time_constant as u32));
+        let pll_shift = 2 * (SHIFT_PLL + 2 + self.time_constant as u32);
+        freq_adj += ((offset as i128) * secs as i128) << (NTP_SCALE_SHIFT - pll_shift);
+        self.time_freq = (freq_adj + self.time_freq as i128)
+            .clamp(-MAXFREQ_SCALED as i128, MAXFREQ_SCALED as i128) as i64;
+
+        self.time_offset = offset << NTP_SCALE_SHIFT;
+    }
+
+    /// Take in `status` at second `now`, with `CLOCK_REALTIME` at second
+    /// `real`.
+    fn process_status(&mut self, status: i32, now: u64, real: u64) {
+        if self.time_status & STA_PLL != 0 && status & STA_PLL == 0 {
+            self.time_state = TimeState::Ok;
+            self.time_status = STA_UNSYNC;
+            self.next_leap_sec = u64::MAX;
+        }
+        if self.time_status & STA_PLL == 0 && status & STA_PLL != 0 {
+            self.time_reftime = now;
+        }
+        self.time_status &= STA_RONLY;
+        self.time_status |= status & !STA_RONLY;
+
+        // Schedule an announced leap second for the next midnight, or withdraw
+        // it. Once it is under way, it runs its course.
+        if matches!(
+            self.time_state,
+            TimeState::Ok | TimeState::Ins | TimeState::Del
+        ) {
+            (self.time_state, self.next_leap_sec) = if self.time_status & STA_INS != 0 {
+                (TimeState::Ins, real - real % SECS_PER_DAY + SECS_PER_DAY)
+            } else if self.time_status & STA_DEL != 0 {
+                (TimeState::Del, real - real % SECS_PER_DAY + SECS_PER_DAY)
+            } else {
+                (TimeState::Ok, u64::MAX)
+            };
+        }
+    }
+
+    fn process_modes(&mut self, tx: &Timex, now: u64, real: u64) {
+        if tx.modes & ADJ_STATUS != 0 {
+            self.process_status(tx.status, now, real);
+        }
+        if tx.modes & ADJ_NANO != 0 {
+            self.time_status |= STA_NANO;
+        }
+        if tx.modes & ADJ_MICRO != 0 {
+            self.time_status &= !STA_NANO;
+        }
+        if tx.modes & ADJ_FREQUENCY != 0 {
+            self.time_freq = (tx.freq * PPM_SCALE).clamp(-MAXFREQ_SCALED, MAXFREQ_SCALED);
+        }
+        if tx.modes & ADJ_MAXERROR != 0 {
+            self.time_maxerror = tx.maxerror;
+        }
+        if tx.modes & ADJ_ESTERROR != 0 {
+            self.time_esterror = tx.esterror;
+        }
+        if tx.modes & ADJ_TIMECONST != 0 {
+            let constant = if self.time_status & STA_NANO == 0 {
+                tx.constant.saturating_add(4)
+            } else {
+                tx.constant
+            };
+            self.time_constant = constant.clamp(0, MAXTC);
+        }
+        if tx.modes & ADJ_TAI != 0 && tx.constant >= 0 {
+            self.tai = tx.constant as i32;
+        }
+        if tx.modes & ADJ_OFFSET != 0 {
+            self.update_offset(tx.offset, now);
+        }
+        if tx.modes & ADJ_TICK != 0 {
+            self.tick_usec = tx.tick;
+        }
+        if tx.modes & (ADJ_TICK | ADJ_FREQUENCY | ADJ_OFFSET) != 0 {
+            self.update_frequency();
+        }
+    }
+
+    /// Apply the modes of `tx` at second `now`, with `CLOCK_REALTIME` at second
+    /// `real`, and report the state in it.
+    pub fn adjtimex(&mut self, tx: &mut Timex, now: u64, real: u64) -> TimeState {
+        if tx.modes & ADJ_ADJTIME != 0 {
+            let pending = self.time_adjust;
+            if tx.modes & ADJ_OFFSET_READONLY == 0 {
+                self.time_adjust = tx.offset;
+                self.update_frequency();
+            }
+            tx.offset = pending;
+        } else {
+            if tx.modes != 0 {
+                self.process_modes(tx, now, real);
+            }
+            tx.offset = shift_right(self.time_offset, NTP_SCALE_SHIFT);
+            if self.time_status & STA_NANO == 0 {
+                tx.offset /= NSEC_PER_USEC;
+            }
+        }
+
+        tx.freq = self.time_freq / PPM_SCALE;
+        tx.maxerror = self.time_maxerror;
+        tx.esterror = self.time_esterror;
+        tx.status = self.time_status;
+        tx.constant = self.time_constant;
+        tx.precision = 1;
+        tx.tolerance = MAXFREQ_SCALED / PPM_SCALE;
+        tx.tick = self.tick_usec;
+        tx.tai = self.tai;
+
+        if is_error_status(self.time_status) {
+            TimeState::Error
+        } else {
+            self.time_state
+        }
+    }
+}
+
+/// Whether the clock cannot be trusted, as reported by [`TimeState::Error`].
+fn is_error_status(status: i32) -> bool {
+    status & (STA_UNSYNC | STA_CLOCKERR) != 0
+        || status & (STA_PPSSIGNAL | STA_PPSFREQ) == STA_PPSFREQ
+        || status & (STA_PPSTIME | STA_PPSJITTER) == (STA_PPSTIME | STA_PPSJITTER)
+        || (status & STA_PPSFREQ != 0 && status & (STA_PPSWANDER | STA_PPSERROR) != 0)
+}
+
+/// Shift rounding towards zero, as Linux does for signed offsets.
+fn shift_right(value: i64, shift: u32) -> i64 {
+    if value < 0 {
+        -((-value) >> shift)
+    } else {
+        value >> shift
+    }
+}
+
+fn validate(tx: &Timex) -> AxResult {
+    if tx.modes & ADJ_ADJTIME != 0 {
+        if tx.modes & ADJ_OFFSET_SINGLESHOT != ADJ_OFFSET_SINGLESHOT {
+            return Err(AxError::InvalidInput);
+        }
+    } else if tx.modes & ADJ_TICK != 0
+        && !(900_000 / USER_HZ..=1_100_000 / USER_HZ).contains(&tx.tick)
+    {
+        return Err(AxError::InvalidInput);
+    }
+    if tx.modes & ADJ_FREQUENCY != 0 && tx.freq.checked_mul(PPM_SCALE).is_none() {
+        return Err(AxError::InvalidInput);
+    }
+    if tx.modes & ADJ_SETOFFSET != 0 {
+        let frac_max = if tx.modes & ADJ_NANO != 0 {
+            NANOS_PER_SEC as i64
+        } else {
+            1_000_000
+        };
+        if !(0..frac_max).contains(&tx.time_usec) {
+            return Err(AxError::InvalidInput);
+        }
+    }
+    Ok(())
+}
+
+/// Read and adjust the kernel clock discipline, as `adjtimex(2)`.
+///
+/// Frequency and offset corrections slew `CLOCK_REALTIME`,
+/// `CLOCK_MONOTONIC` and the clocks derived from them, while `ADJ_SETOFFSET`
+/// steps `CLOCK_REALTIME` as [`set_realtime`](crate::timekeeping::set_realtime)
+/// does. Either is published to the vDSO right away. The caller is responsible
+/// for checking that the process may set the time when `tx.modes` is not zero.
+pub fn adjtimex(tx: &mut Timex) -> AxResult<TimeState> {
+    validate(tx)?;
+    let mut tk = TIMEKEEPER.lock();
+    // Finish the current interval at the old rate.
+    tk.advance(current_ticks());
+    if tx.modes & ADJ_SETOFFSET != 0 {
+        let frac = if tx.modes & ADJ_NANO != 0 {
+            tx.time_usec
+        } else {
+            tx.time_usec * NSEC_PER_USEC
+        };
+        let step = tx.time_sec as i128 * NANOS_PER_SEC as i128 + frac as i128;
+        let realtime = tk.realtime_ns() as i128 + step;
+        if !(0..i64::MAX as i128).contains(&realtime) {
+            return Err(AxError::InvalidInput);
+        }
+        tk.set_realtime(realtime as u64);
+    }
+    let now = tk.mono.sec;
+    let real = tk.realtime_ns() / NANOS_PER_SEC;
+    let state = tk.ntp.adjtimex(tx, now, real);
+    tk.update_mult();
+
+    let realtime = tk.realtime_ns();
+    tx.time_sec = (realtime / NANOS_PER_SEC) as i64;
+    tx.time_usec = (realtime % NANOS_PER_SEC) as i64;
+    if tx.status & STA_NANO == 0 {
+        tx.time_usec /= NSEC_PER_USEC;
+    }
+    publish_vdso_data(&tk);
+    Ok(state)
+}
+
+/// Smear leap seconds over `window` seconds centred on them instead of
+/// stepping `CLOCK_REALTIME`, or step again if `window` is zero.
+///
+/// The smear slews `CLOCK_REALTIME` alone, by `1 / window`, through its offset
+/// from `CLOCK_MONOTONIC`, which keeps its rate along with the kernel's timers.
+/// Each update takes in the smear due since the previous one. While a second
+/// is inserted, the vDSO extrapolates from an update at the smeared rate, see
+/// [`clock_gettime_nanos`](crate::timekeeping::clock_gettime_nanos), so that
+/// `CLOCK_REALTIME` does not step back at the next one. A leap second already
+/// being smeared is not affected.
+pub fn set_leap_smear(window: u32) {
+    TIMEKEEPER.lock().ntp.leap_smear = window;
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::ops::Range;
+    use std::vec::Vec;
+
+    use super::*;
+
+    const SECOND: i64 = (NANOS_PER_SEC as i64) << NTP_SCALE_SHIFT;
+
+    /// A discipline with the PLL enabled at second `now`.
+    fn pll(now: u64) -> NtpState {
+        let mut ntp = NtpState::new();
+        let mut tx = Timex {
+            modes: ADJ_STATUS | ADJ_NANO,
+            status: STA_PLL,
+            ..Default::default()
+        };
+        ntp.adjtimex(&mut tx, now, 0);
+        ntp
+    }
+
+    fn offset(ntp: &mut NtpState, offset: i64, now: u64) {
+        let mut tx = Timex {
+            modes: ADJ_OFFSET,
+            offset,
+            ..Default::default()
+        };
+        ntp.adjtimex(&mut tx, now, 0);
+    }
+
+    const MIDNIGHT: u64 = 19_723 * SECS_PER_DAY;
+
+    /// Announce `status` with `CLOCK_REALTIME` at second `real`.
+    fn announce(ntp: &mut NtpState, status: i32, real: u64) -> TimeState {
+        let mut tx = Timex {
+            modes: ADJ_STATUS,
+            status,
+            ..Default::default()
+        };
+        ntp.adjtimex(&mut tx, 0, real)
+    }
+
+    /// Run the leap second state machine over `secs` and return the steps.
+    fn run(ntp: &mut NtpState, secs: Range<u64>) -> Vec<(u64, i64)> {
+        secs.map(|sec| (sec, ntp.leap_second(sec)))
+            .filter(|&(_, leap)| leap != 0)
+            .collect()
+    }
+
+    #[test]
+    fn insert_leap_second() {
+        let mut ntp = NtpState::new();
+        ntp.tai = 37;
+        assert_eq!(
+            announce(&mut ntp, STA_PLL | STA_INS, MIDNIGHT - 100),
+            TimeState::Ins
+        );
+        assert_eq!(ntp.next_leap_sec, MIDNIGHT);
+        assert_eq!(run(&mut ntp, MIDNIGHT - 99..MIDNIGHT + 5), [(MIDNIGHT, -1)]);
+        assert_eq!(ntp.tai, 38);
+        assert_eq!(ntp.time_state, TimeState::Wait);
+        // Waits for the announcement to be cleared.
+        run(&mut ntp, MIDNIGHT + 5..MIDNIGHT + 10);
+        assert_eq!(ntp.time_state, TimeState::Wait);
+        assert_eq!(announce(&mut ntp, STA_PLL, MIDNIGHT + 10), TimeState::Wait);
+        run(&mut ntp, MIDNIGHT + 10..MIDNIGHT + 11);
+        assert_eq!(ntp.time_state, TimeState::Ok);
+    }
+
+    #[test]
+    fn delete_leap_second() {
+        let mut ntp = NtpState::new();
+        ntp.tai = 37;
+        assert_eq!(
+            announce(&mut ntp, STA_PLL | STA_DEL, MIDNIGHT - 100),
+            TimeState::Del
+        );
+        // 23:59:59 is skipped.
+        assert_eq!(
+            run(&mut ntp, MIDNIGHT - 99..MIDNIGHT + 5),
+            [(MIDNIGHT - 1, 1)]
+        );
+        assert_eq!(ntp.tai, 36);
+        assert_eq!(ntp.time_state, TimeState::Wait);
+    }
+
+    #[test]
+    fn late_update_still_applies_leap_second() {
+        let mut ntp = NtpState::new();
+        announce(&mut ntp, STA_PLL | STA_INS, MIDNIGHT - 100);
+        // The clock was stepped, or updates were held off, past midnight.
+        assert_eq!(ntp.leap_second(MIDNIGHT - 50), 0);
+        assert_eq!(ntp.leap_second(MIDNIGHT + 3), -1);
+    }
+
+    #[test]
+    fn withdraw_leap_second() {
+        let mut ntp = NtpState::new();
+        announce(&mut ntp, STA_PLL | STA_INS, MIDNIGHT - 100);
+        assert_eq!(announce(&mut ntp, STA_PLL, MIDNIGHT - 50), TimeState::Ok);
+        assert_eq!(ntp.next_leap_sec, u64::MAX);
+        assert_eq!(run(&mut ntp, MIDNIGHT - 49..MIDNIGHT + 5), []);
+
+        // Switching to a deletion reschedules.
+        announce(&mut ntp, STA_PLL | STA_INS, MIDNIGHT - 100);
+        assert_eq!(
+            announce(&mut ntp, STA_PLL | STA_DEL, MIDNIGHT - 50),
+            TimeState::Del
+        );
+        assert_eq!(
+            run(&mut ntp, MIDNIGHT - 49..MIDNIGHT + 5),
+            [(MIDNIGHT - 1, 1)]
+        );
+
+        // So does turning off the PLL.
+        let mut ntp = NtpState::new();
+        announce(&mut ntp, STA_PLL | STA_INS, MIDNIGHT - 100);
+        assert_eq!(announce(&mut ntp, 0, MIDNIGHT - 50), TimeState::Ok);
+        assert_eq!(run(&mut ntp, MIDNIGHT - 49..MIDNIGHT + 5), []);
+    }
+
+    #[test]
+    fn smear_leap_second() {
+        let mut ntp = NtpState::new();
+        ntp.tai = 37;
+        ntp.leap_smear = 100;
+        announce(&mut ntp, STA_PLL | STA_INS, MIDNIGHT - 1000);
+        // Starts half the window before midnight, without stepping.
+        assert_eq!(run(&mut ntp, MIDNIGHT - 999..MIDNIGHT - 50), []);
+        assert_eq!(ntp.time_state, TimeState::Ins);
+        assert_eq!(ntp.leap_second(MIDNIGHT - 50), 0);
+        assert_eq!(ntp.time_state, TimeState::Oop);
+        assert_eq!(ntp.smear_rate(), -SECOND / 100);
+
+        let mut smeared = 0;
+        for _ in 0..1000 {
+            smeared += ntp.accrue((NANOS_PER_SEC / 10) as u128, 0);
+            // CLOCK_TAI keeps counting.
+            assert_eq!(ntp.tai_offset_ns(), 37 * NANOS_PER_SEC as i64 - smeared);
+        }
+        assert_eq!(smeared, -(NANOS_PER_SEC as i64));
+        assert_eq!((ntp.tai, ntp.smear_rate()), (38, 0));
+        assert_eq!(ntp.time_state, TimeState::Wait);
+    }
+
+    #[test]
+    fn frequency_sets_tick_length() {
+        let mut ntp = NtpState::new();
+        let mut tx = Timex {
+            modes: ADJ_FREQUENCY,
+            freq: 10 << SHIFT_USEC,
+            ..Default::default()
+        };
+        ntp.adjtimex(&mut tx, 0, 0);
+        ntp.second_overflow();
+        assert_eq!(ntp.tick_length(), SECOND + (10_000 << NTP_SCALE_SHIFT));
+        assert_eq!(tx.freq, 10 << SHIFT_USEC);
+    }
+
+    #[test]
+    fn pll_slews_offset_and_integrates_frequency() {
+        let mut ntp = pll(100);
+        offset(&mut ntp, 1_000_000, 100);
+        // No time has passed yet, so only the phase is corrected.
+        assert_eq!(ntp.time_freq, 0);
+        let mut slewed = 0;
+        for _ in 0..4 {
+            ntp.second_overflow();
+            slewed += ntp.tick_length() - SECOND;
+        }
+        // 1/16 of the remaining offset per second with the default constant.
+        let mut left = 1_000_000i64 << NTP_SCALE_SHIFT;
+        for _ in 0..4 {
+            left -= left >> (SHIFT_PLL + 2);
+        }
+        assert_eq!(slewed, (1_000_000 << NTP_SCALE_SHIFT) - left);
+
+        offset(&mut ntp, 1_000, 110);
+        let pll_shift = 2 * (SHIFT_PLL + 2 + 2);
+        assert_eq!(ntp.time_freq, (1_000 * 10) << (NTP_SCALE_SHIFT - pll_shift));
+        assert_eq!(ntp.time_status & STA_MODE, 0);
+    }
+
+    #[test]
+    fn pll_interval_is_clamped() {
+        let pll_shift = 2 * (SHIFT_PLL + 2 + 2);
+        let clamped = (1_000 << (SHIFT_PLL + 1 + 2)) << (NTP_SCALE_SHIFT - pll_shift);
+        for secs in [32, 100, MINSEC - 1] {
+            let mut ntp = pll(0);
+            offset(&mut ntp, 1_000, secs);
+            assert_eq!(ntp.time_freq, clamped, "after {secs}s");
+        }
+    }
+
+    #[test]
+    fn fll_for_long_intervals() {
+        let pll_gain =
+            (1_000 << (SHIFT_PLL + 1 + 2)) << (NTP_SCALE_SHIFT - 2 * (SHIFT_PLL + 2 + 2));
+        let mut ntp = pll(0);
+        offset(&mut ntp, 1_000, MAXSEC + 1);
+        assert_ne!(ntp.time_status & STA_MODE, 0);
+        assert_eq!(
+            ntp.time_freq,
+            ((1_000 << (NTP_SCALE_SHIFT - SHIFT_FLL)) / (MAXSEC + 1) as i64) + pll_gain
+        );
+
+        // STA_FLL selects it from MINSEC on.
+        let mut ntp = pll(0);
+        let mut tx = Timex {
+            modes: ADJ_STATUS,
+            status: STA_PLL | STA_FLL | STA_NANO,
+            ..Default::default()
+        };
+        ntp.adjtimex(&mut tx, 0, 0);
+        offset(&mut ntp, 1_000, MINSEC);
+        assert_ne!(ntp.time_status & STA_MODE, 0);
+        assert_eq!(
+            ntp.time_freq,
+            ((1_000 << (NTP_SCALE_SHIFT - SHIFT_FLL)) / MINSEC as i64) + pll_gain
+        );
+    }
+
+    #[test]
+    fn frequency_is_clamped() {
+        let mut ntp = pll(0);
+        ntp.time_freq = MAXFREQ_SCALED - 1;
+        offset(&mut ntp, MAXPHASE, 30);
+        assert_eq!(ntp.time_freq, MAXFREQ_SCALED);
+    }
+
+    #[test]
+    fn adjtime_slews_500us_per_second() {
+        let mut ntp = NtpState::new();
+        let mut tx = Timex {
+            modes: ADJ_OFFSET_SINGLESHOT,
+            offset: -1_200,
+            ..Default::default()
+        };
+        ntp.adjtimex(&mut tx, 0, 0);
+        let mut lengths = [0; 4];
+        for length in &mut lengths {
+            ntp.second_overflow();
+            *length = (ntp.tick_length() - SECOND) >> NTP_SCALE_SHIFT;
+        }
+        assert_eq!(lengths, [-500_000, -500_000, -200_000, 0]);
+
+        let mut tx = Timex {
+            modes: ADJ_OFFSET_SS_READ,
+            ..Default::default()
+        };
+        ntp.adjtimex(&mut tx, 0, 0);
+        assert_eq!(tx.offset, 0);
+    }
+}
@@ -0,0 +1,149 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::warn;
+
+/// Bit for the single-letter ISA extension `ext`, as in Linux
+/// `COMPAT_HWCAP_ISA_*` and `misa`.
+const fn isa(ext: u8) -> usize {
+    1 << (ext - b'a')
+}
+
+/// The extensions Linux reports in `AT_HWCAP`.
+const HWCAP_MASK: usize =
+    isa(b'i') | isa(b'm') | isa(b'a') | isa(b'f') | isa(b'd') | isa(b'c') | isa(b'v');
+
+/// Extensions common to all harts reported so far, or 0 before the first.
+static HART_ISA: AtomicUsize = AtomicUsize::new(0);
+
+fn report_hart(caps: usize) {
+    let mut caps = caps & HWCAP_MASK;
+    // As in Linux, F is only usable together with D.
+    if caps & isa(b'd') == 0 {
+        caps &= !isa(b'f');
+    }
+    let _ = HART_ISA.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+        Some(if old == 0 { caps } else { old & caps })
+    });
+}
+
+/// Parse the single-letter extensions of a `riscv,isa` string.
+fn parse_isa(isa_str: &str) -> Option<usize> {
+    let prefix = isa_str.get(..4)?;
+    if !prefix.eq_ignore_ascii_case("rv64") {
+        return None;
+    }
+    let mut caps = 0;
+    let mut prev_digit = false;
+    let mut multi_letter = false;
+    for c in isa_str[4..].bytes().map(|c| c.to_ascii_lowercase()) {
+        match c {
+            b'_' => multi_letter = false,
+            _ if multi_letter => {}
+            b's' | b'x' | b'z' => multi_letter = true,
+            b'0'..=b'9' => {}
+            // Version separator, as in `i2p1`.
+            b'p' if prev_digit => {}
+            b'g' => caps |= isa(b'i') | isa(b'm') | isa(b'a') | isa(b'f') | isa(b'd'),
+            b'a'..=b'z' => caps |= isa(c),
+            _ => return None,
+        }
+        prev_digit = c.is_ascii_digit();
+    }
+    Some(caps)
+}
+
+/// Report the device-tree `riscv,isa` string of a hart.
+///
+/// Call this for every hart at boot; `AT_HWCAP` then holds the extensions
+/// all of them implement, as on Linux.
+pub fn set_hart_isa(isa_str: &str) {
+    match parse_isa(isa_str) {
+        Some(caps) => report_hart(caps),
+        None => warn!("ignoring malformed riscv,isa string {isa_str:?}"),
+    }
+}
+
+/// Report the `misa` CSR of a hart, as read by firmware or M-mode code.
+///
+/// Like [`set_hart_isa`], for platforms without a device tree.
+pub fn set_hart_misa(misa: usize) {
+    report_hart(misa);
+}
+
+/// Extensions the kernel itself was built for, which every hart running it
+/// implements.
+fn built_for() -> usize {
+    let mut caps = isa(b'i');
+    if cfg!(target_feature = "m") {
+        caps |= isa(b'm');
+    }
+    if cfg!(target_feature = "a") {
+        caps |= isa(b'a');
+    }
+    if cfg!(target_feature = "f") {
+        caps |= isa(b'f');
+    }
+    if cfg!(target_feature = "d") {
+        caps |= isa(b'd');
+    }
+    if cfg!(target_feature = "c") {
+        caps |= isa(b'c');
+    }
+    if cfg!(target_feature = "v") {
+        caps |= isa(b'v');
+    }
+    caps
+}
+
+/// `AT_HWCAP`: the single-letter base ISA extensions of the platform.
+///
+/// `misa` is not readable from S-mode, so this relies on the harts reported
+/// through [`set_hart_isa`] or [`set_hart_misa`]. Until one is, it reports
+/// the extensions the kernel was built for.
+pub fn hwcap() -> usize {
+    match HART_ISA.load(Ordering::Relaxed) {
+        0 => built_for(),
+        caps => caps,
+    }
+}
+
+/// `AT_HWCAP2`: unused on riscv64.
+pub fn hwcap2() -> usize {
+    0
+}
+
+/// `AT_MINSIGSTKSZ` for a signal frame of `frame_size` bytes.
+pub fn minsigstksz(frame_size: usize) -> usize {
+    frame_size.next_multiple_of(16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_single_letter_extensions() {
+        let imafdc = isa(b'i') | isa(b'm') | isa(b'a') | isa(b'f') | isa(b'd') | isa(b'c');
+        assert_eq!(parse_isa("rv64imafdc"), Some(imafdc));
+        assert_eq!(parse_isa("RV64GC"), Some(imafdc));
+        assert_eq!(parse_isa("rv64i2p1m2a2f2d2c"), Some(imafdc));
+        assert_eq!(
+            parse_isa("rv64imac_zicsr_zifencei_svpbmt"),
+            Some(imafdc & !isa(b'f') & !isa(b'd'))
+        );
+        // Letters of multi-letter extensions are not single-letter ones.
+        assert_eq!(parse_isa("rv64i_xtheadvector"), Some(isa(b'i')));
+        assert_eq!(
+            parse_isa("rv64imsvinval_v"),
+            Some(isa(b'i') | isa(b'm') | isa(b'v'))
+        );
+    }
+
+    #[test]
+    fn reject_malformed() {
+        assert_eq!(parse_isa("rv32imac"), None);
+        assert_eq!(parse_isa("rv6"), None);
+        assert_eq!(parse_isa("rv64im-ac"), None);
+        assert_eq!(parse_isa(""), None);
+    }
+}
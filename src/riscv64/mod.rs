@@ -1,2 +1,3 @@
 pub mod config;
+pub mod hwcap;
 pub mod vdso_data;
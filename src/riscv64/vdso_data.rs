@@ -1,8 +1,13 @@
-use crate::vdso_time_data::VdsoTimeData;
+use crate::{PAGE_SIZE, config::VVAR_PAGES, timekeeping::Timekeeper, vdso_time_data::VdsoTimeData};
+
+/// Zeroes filling the rest of the vvar area, which is mapped to user space
+/// in full.
+const PAD_SIZE: usize = VVAR_PAGES * PAGE_SIZE - size_of::<VdsoTimeData>();
 
 #[repr(C)]
 pub struct VdsoData {
     pub time_data: VdsoTimeData,
+    _pad: [u8; PAD_SIZE],
 }
 
 impl Default for VdsoData {
@@ -15,10 +20,11 @@ impl VdsoData {
     pub const fn new() -> Self {
         Self {
             time_data: VdsoTimeData::new(),
+            _pad: [0u8; PAD_SIZE],
         }
     }
 
-    pub fn time_update(&mut self) {
-        self.time_data.update();
+    pub(crate) fn time_update(&mut self, tk: &Timekeeper) {
+        self.time_data.update(tk);
     }
 }
@@ -0,0 +1,408 @@
+//! Kernel-side state of the clocks published in the vDSO time data.
+//!
+//! As in Linux, each clock is advanced by accumulating counter deltas scaled
+//! by its own `mult`, keeping nanoseconds shifted left by `shift`. The vDSO
+//! extrapolates from the last published state with the same arithmetic, so a
+//! clock advances continuously across updates even when its `mult` changes.
+use axplat::time::{
+    NANOS_PER_SEC, current_ticks, monotonic_time_nanos, nanos_to_ticks, wall_time_nanos,
+};
+use kspin::SpinNoIrq;
+
+use crate::{
+    ntp::{NTP_SCALE_SHIFT, NtpState},
+    vdso::publish_vdso_data,
+    vdso_time_data::{
+        CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_MONOTONIC_COARSE, CLOCK_MONOTONIC_RAW,
+        CLOCK_REALTIME, CLOCK_REALTIME_COARSE, CLOCK_TAI, clocks_calc_mult_shift,
+    },
+};
+
+/// A clock driven by the counter.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClockBase {
+    /// Counter value at the base.
+    pub cycle_last: u64,
+    /// Nanoseconds per cycle, scaled by `1 << shift`.
+    pub mult: u32,
+    pub shift: u32,
+    /// Whole seconds at the base.
+    pub sec: u64,
+    /// Nanoseconds within `sec` at the base, shifted left by `shift`.
+    pub nsec: u64,
+}
+
+impl ClockBase {
+    const fn new() -> Self {
+        Self {
+            cycle_last: 0,
+            mult: 0,
+            shift: 0,
+            sec: 0,
+            nsec: 0,
+        }
+    }
+
+    /// Restart the clock at `ns` as of `cycle_now`.
+    fn reset(&mut self, cycle_now: u64, ns: u64, (mult, shift): (u32, u32)) {
+        self.cycle_last = cycle_now;
+        self.mult = mult;
+        self.shift = shift;
+        self.sec = ns / NANOS_PER_SEC;
+        self.nsec = (ns % NANOS_PER_SEC) << shift;
+    }
+
+    /// Move the base to `cycle_now`, accumulating the elapsed cycles, and
+    /// return their number.
+    fn advance(&mut self, cycle_now: u64) -> u64 {
+        let delta = cycle_now.wrapping_sub(self.cycle_last);
+        let one_sec = (NANOS_PER_SEC as u128) << self.shift;
+        let nsec = self.nsec as u128 + delta as u128 * self.mult as u128;
+        self.sec += (nsec / one_sec) as u64;
+        self.nsec = (nsec % one_sec) as u64;
+        self.cycle_last = cycle_now;
+        delta
+    }
+
+    /// Nanoseconds at `cycle_now`, as the vDSO computes them.
+    pub fn ns_at(&self, cycle_now: u64) -> u64 {
+        let delta = cycle_now.wrapping_sub(self.cycle_last);
+        let nsec = (self.nsec as u128 + delta as u128 * self.mult as u128) >> self.shift;
+        self.sec * NANOS_PER_SEC + nsec as u64
+    }
+
+    /// Nanoseconds at `cycle_now` of a clock `offset` shifted nanoseconds
+    /// ahead of this one, as the vDSO computes them from its base.
+    pub fn ns_at_offset(&self, cycle_now: u64, offset: i128) -> u64 {
+        let delta = cycle_now.wrapping_sub(self.cycle_last);
+        let shifted = ((self.sec as i128 * NANOS_PER_SEC as i128) << self.shift)
+            + self.nsec as i128
+            + offset
+            + delta as i128 * self.mult as i128;
+        (shifted >> self.shift) as u64
+    }
+
+    /// Nanoseconds at the base.
+    pub fn ns(&self) -> u64 {
+        self.sec * NANOS_PER_SEC + (self.nsec >> self.shift)
+    }
+}
+
+/// The counter-driven clocks.
+pub(crate) struct Timekeeper {
+    /// Base of `CLOCK_MONOTONIC` and the clocks derived from it.
+    pub mono: ClockBase,
+    /// Base of `CLOCK_MONOTONIC_RAW`, which always counts at the nominal
+    /// counter frequency.
+    pub raw: ClockBase,
+    /// `CLOCK_REALTIME` minus `CLOCK_MONOTONIC`, in nanoseconds.
+    pub offs_real: i64,
+    /// Clock discipline slewing `mono`.
+    pub ntp: NtpState,
+    /// `mult` of the nominal counter frequency.
+    nominal_mult: u32,
+    /// `mult` of `mono` that would give the length of the current second
+    /// requested by `ntp`, with 32 fractional bits.
+    ideal_mult: u64,
+    /// How far `mono` lags behind the ideal clock, in shifted nanoseconds.
+    ntp_error: i128,
+    /// Second of `mono` up to which `ntp` has been run.
+    ntp_sec: u64,
+    /// Second of `CLOCK_REALTIME` up to which leap seconds have been checked.
+    leap_sec: u64,
+    initialized: bool,
+}
+
+impl Timekeeper {
+    const fn new() -> Self {
+        Self {
+            mono: ClockBase::new(),
+            raw: ClockBase::new(),
+            offs_real: 0,
+            ntp: NtpState::new(),
+            nominal_mult: 0,
+            ideal_mult: 0,
+            ntp_error: 0,
+            ntp_sec: 0,
+            leap_sec: 0,
+            initialized: false,
+        }
+    }
+
+    /// Advance every clock to `cycle_now`.
+    ///
+    /// The first call starts both clocks at the platform's monotonic time, and
+    /// `CLOCK_REALTIME` at its wall time.
+    pub fn advance(&mut self, cycle_now: u64) {
+        if !self.initialized {
+            let ticks_per_sec = nanos_to_ticks(NANOS_PER_SEC);
+            let mult_shift = clocks_calc_mult_shift(ticks_per_sec, NANOS_PER_SEC, 10);
+            let mono_ns = monotonic_time_nanos();
+            self.mono.reset(cycle_now, mono_ns, mult_shift);
+            self.raw.reset(cycle_now, mono_ns, mult_shift);
+            self.offs_real = wall_time_nanos() as i64 - mono_ns as i64;
+            self.nominal_mult = mult_shift.0;
+            self.ntp_sec = self.mono.sec;
+            self.leap_sec = self.realtime_ns() / NANOS_PER_SEC;
+            self.initialized = true;
+            self.update_mult();
+            return;
+        }
+        let mult = self.mono.mult;
+        let delta = self.mono.advance(cycle_now);
+        self.offs_real += self
+            .ntp
+            .accrue(delta as u128 * mult as u128, self.mono.shift);
+        self.ntp_error += ((delta as i128 * self.ideal_mult as i128) >> 32)
+            - delta as i128 * self.mono.mult as i128;
+        self.raw.advance(cycle_now);
+
+        while self.ntp_sec < self.mono.sec {
+            self.ntp.second_overflow();
+            self.ntp_sec += 1;
+        }
+
+        let mut now = self.realtime_ns() / NANOS_PER_SEC;
+        while self.leap_sec < now {
+            self.leap_sec += 1;
+            let leap = self.ntp.leap_second(self.leap_sec);
+            if leap != 0 {
+                self.offs_real += leap * NANOS_PER_SEC as i64;
+                self.leap_sec = self.leap_sec.wrapping_add_signed(leap);
+                now = self.realtime_ns() / NANOS_PER_SEC;
+            }
+        }
+        self.update_mult();
+    }
+
+    /// Derive the `mult` of `mono` from the second length requested by `ntp`.
+    ///
+    /// The exact `mult` is rarely an integer, so alternate between the two
+    /// nearest ones to keep `mono` on the ideal clock over time.
+    pub fn update_mult(&mut self) {
+        self.ideal_mult = (self.nominal_mult as u128 * self.ntp.tick_length() as u128
+            / NANOS_PER_SEC as u128) as u64;
+        let mult = (self.ideal_mult >> 32) + (self.ntp_error > 0) as u64;
+        self.mono.mult = mult.min(u32::MAX as u64) as u32;
+    }
+
+    /// `mono` as published to the vDSO, for the clocks sharing its counter
+    /// parameters.
+    ///
+    /// While a leap second is inserted by smearing, `CLOCK_REALTIME` runs
+    /// slower than `mono` but only takes in the smear at updates. Extrapolated
+    /// at the rate of `mono` it would overshoot and step back at every update,
+    /// so all of them are extrapolated at the smeared rate, rounded down, and
+    /// step forward instead.
+    pub fn hres_clock(&self) -> ClockBase {
+        let rate = self.ntp.smear_rate();
+        if rate >= 0 {
+            return self.mono;
+        }
+        let mult = self.mono.mult as i128;
+        let slowdown = (mult * rate as i128).div_euclid((NANOS_PER_SEC as i128) << NTP_SCALE_SHIFT);
+        ClockBase {
+            mult: (mult + slowdown - 1).max(0) as u32,
+            ..self.mono
+        }
+    }
+
+    /// `CLOCK_REALTIME` minus `CLOCK_MONOTONIC` in nanoseconds shifted left by
+    /// the shift of `mono`, including the part of a nanosecond a leap second
+    /// smear has taken in.
+    pub fn offs_real_shifted(&self) -> i128 {
+        let frac = self.ntp.smear_frac() >> (NTP_SCALE_SHIFT - self.mono.shift);
+        ((self.offs_real as i128) << self.mono.shift) + frac as i128
+    }
+
+    /// `CLOCK_REALTIME` at the base, in nanoseconds.
+    pub fn realtime_ns(&self) -> u64 {
+        self.mono.ns().wrapping_add_signed(self.offs_real)
+    }
+
+    /// Step `CLOCK_REALTIME` to `ns` at the base.
+    pub fn set_realtime(&mut self, ns: u64) {
+        self.offs_real = ns as i64 - self.mono.ns() as i64;
+        self.leap_sec = ns / NANOS_PER_SEC;
+    }
+}
+
+/// The clocks, updated from the timer interrupt as well as from syscalls, so
+/// the lock masks interrupts.
+pub(crate) static TIMEKEEPER: SpinNoIrq<Timekeeper> = SpinNoIrq::new(Timekeeper::new());
+
+/// Step `CLOCK_REALTIME` to `ns` since the Epoch, as `clock_settime(2)` and
+/// `settimeofday(2)` do.
+///
+/// `CLOCK_TAI` steps along, the monotonic clocks are left alone. The new time
+/// is published to the vDSO before returning.
+pub fn set_realtime(ns: u64) {
+    let mut tk = TIMEKEEPER.lock();
+    tk.advance(current_ticks());
+    tk.set_realtime(ns);
+    publish_vdso_data(&tk);
+}
+
+/// Set the offset of `CLOCK_TAI` from `CLOCK_REALTIME` to `secs` seconds and
+/// publish it to the vDSO.
+pub fn set_tai_offset(secs: i32) {
+    let mut tk = TIMEKEEPER.lock();
+    tk.advance(current_ticks());
+    tk.ntp.tai = secs;
+    publish_vdso_data(&tk);
+}
+
+/// Current time of clock `clock_id` in nanoseconds, for `clock_gettime(2)`
+/// and `gettimeofday(2)`.
+///
+/// Computed from the state last published to the vDSO with the arithmetic of
+/// the vDSO, so that the syscalls and the vDSO agree, also around leap
+/// seconds. While a leap second is inserted by smearing, the high resolution
+/// clocks other than `CLOCK_MONOTONIC_RAW` run marginally slow between updates
+/// and catch up at each, see [`set_leap_smear`](crate::ntp::set_leap_smear).
+/// Returns `None` for clocks not kept here, or before the first
+/// [`update_vdso_data`](crate::vdso::update_vdso_data).
+pub fn clock_gettime_nanos(clock_id: u32) -> Option<u64> {
+    let tk = TIMEKEEPER.lock();
+    if !tk.initialized {
+        return None;
+    }
+    let cycle_now = current_ticks();
+    let hres = tk.hres_clock();
+    let ns = match clock_id as usize {
+        CLOCK_REALTIME => hres.ns_at_offset(cycle_now, tk.offs_real_shifted()),
+        CLOCK_MONOTONIC | CLOCK_BOOTTIME => hres.ns_at(cycle_now),
+        CLOCK_MONOTONIC_RAW => tk.raw.ns_at(cycle_now),
+        CLOCK_REALTIME_COARSE => tk.mono.ns().wrapping_add_signed(tk.offs_real),
+        CLOCK_MONOTONIC_COARSE => tk.mono.ns(),
+        CLOCK_TAI => {
+            let offs_tai = (tk.offs_real + tk.ntp.tai_offset_ns()) as i128;
+            hres.ns_at_offset(cycle_now, offs_tai << hres.shift)
+        }
+        _ => return None,
+    };
+    Some(ns)
+}
+
+/// Current `CLOCK_MONOTONIC` in nanoseconds, for the kernel's own timers.
+///
+/// NTP slews this clock away from the platform's monotonic time by up to
+/// 500 ppm. Sleeps, timers and `TIMER_ABSTIME` deadlines in terms of
+/// `CLOCK_MONOTONIC` have to be checked against this clock for the kernel to
+/// agree with user space. Unlike [`clock_gettime_nanos`], this follows the
+/// clock exactly while a leap second is smeared, so it may run up to the smear
+/// rate times the update interval ahead of user space then. Before the first
+/// [`update_vdso_data`](crate::vdso::update_vdso_data) this is the
+/// platform's monotonic time.
+pub fn monotonic_nanos() -> u64 {
+    let tk = TIMEKEEPER.lock();
+    if !tk.initialized {
+        return monotonic_time_nanos();
+    }
+    tk.mono.ns_at(current_ticks())
+}
+
+/// Current `CLOCK_REALTIME` in nanoseconds since the Epoch, for the kernel's
+/// own timestamps. Reads the same as [`clock_gettime_nanos`], so it never
+/// steps back while a leap second is smeared.
+pub fn realtime_nanos() -> u64 {
+    let tk = TIMEKEEPER.lock();
+    if !tk.initialized {
+        return wall_time_nanos();
+    }
+    tk.hres_clock()
+        .ns_at_offset(current_ticks(), tk.offs_real_shifted())
+}
+
+/// Counter value at which [`monotonic_nanos`] reaches `deadline`, for arming
+/// a one-shot timer.
+///
+/// Assumes the current rate of the clock. Should a correction change it in
+/// the meantime, the timer fires marginally early or late, so check the
+/// deadline again when it does.
+pub fn monotonic_deadline_ticks(deadline: u64) -> u64 {
+    let tk = TIMEKEEPER.lock();
+    if !tk.initialized {
+        return nanos_to_ticks(deadline);
+    }
+    let mono = &tk.mono;
+    let base = ((mono.sec as u128 * NANOS_PER_SEC as u128) << mono.shift) + mono.nsec as u128;
+    let target = (deadline as u128) << mono.shift;
+    let cycles = target
+        .saturating_sub(base)
+        .div_ceil(mono.mult.max(1) as u128);
+    mono.cycle_last
+        .saturating_add(cycles.min(u64::MAX as u128) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntp::{ADJ_STATUS, STA_INS, STA_PLL, Timex};
+
+    /// A timekeeper on a 19.2 MHz counter, with `CLOCK_REALTIME` at second
+    /// `real`.
+    fn timekeeper(real: u64) -> Timekeeper {
+        let mut tk = Timekeeper::new();
+        let mult_shift = clocks_calc_mult_shift(19_200_000, NANOS_PER_SEC, 10);
+        tk.mono.reset(0, NANOS_PER_SEC, mult_shift);
+        tk.raw.reset(0, NANOS_PER_SEC, mult_shift);
+        tk.offs_real = ((real - 1) * NANOS_PER_SEC) as i64;
+        tk.nominal_mult = mult_shift.0;
+        tk.ntp_sec = tk.mono.sec;
+        tk.leap_sec = real;
+        tk.initialized = true;
+        tk.update_mult();
+        tk
+    }
+
+    /// `CLOCK_REALTIME`, `CLOCK_MONOTONIC` and `CLOCK_TAI` at `cycles`, as
+    /// published.
+    fn read(tk: &Timekeeper, cycles: u64) -> [u64; 3] {
+        let hres = tk.hres_clock();
+        let offs_tai = ((tk.offs_real + tk.ntp.tai_offset_ns()) as i128) << hres.shift;
+        [
+            hres.ns_at_offset(cycles, tk.offs_real_shifted()),
+            hres.ns_at(cycles),
+            hres.ns_at_offset(cycles, offs_tai),
+        ]
+    }
+
+    #[test]
+    fn smeared_insertion_never_steps_back() {
+        let midnight = 19_723 * 86_400;
+        let mut tk = timekeeper(midnight - 20);
+        tk.ntp.leap_smear = 10;
+        let mut tx = Timex {
+            modes: ADJ_STATUS,
+            status: STA_PLL | STA_INS,
+            ..Default::default()
+        };
+        tk.ntp.adjtimex(&mut tx, tk.mono.sec, midnight - 20);
+
+        let start = read(&tk, 0);
+        let mut last = start;
+        let mut cycles = 0;
+        let mut slowed = false;
+        // 40s of uneven 100 Hz ticks, read from the start of each to its end.
+        for i in 0..4000 {
+            let tick = 192_000 + i % 7 * 13;
+            for k in 0..=4 {
+                let now = read(&tk, cycles + tick * k / 4);
+                for (clock, (now, last)) in now.iter().zip(&last).enumerate() {
+                    assert!(now >= last, "clock {clock} stepped back at tick {i}");
+                }
+                last = now;
+            }
+            cycles += tick;
+            tk.advance(cycles);
+            slowed |= tk.hres_clock().mult < tk.mono.mult;
+        }
+        assert!(slowed);
+
+        let end = read(&tk, cycles);
+        assert_eq!(tk.hres_clock().mult, tk.mono.mult);
+        assert_eq!(end[1] - start[1], end[0] - start[0] + NANOS_PER_SEC);
+        assert_eq!(end[2] - start[2], end[1] - start[1]);
+    }
+}
@@ -0,0 +1,485 @@
+//! Structural validation of the embedded vDSO image.
+//!
+//! The image is mapped verbatim into every user process, so it is checked
+//! against the build-time expectations before the loader trusts any of its
+//! headers. Fields are read directly from the bytes rather than through an ELF
+//! parser so that a malformed image cannot send the parser out of bounds.
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use memory_addr::MemoryAddr;
+
+use crate::{
+    PAGE_SIZE,
+    config::{ELF_MACHINE, IMAGE_HASH},
+};
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const DYN_SIZE: usize = 16;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_DYN: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_STRSZ: u64 = 10;
+const DT_GNU_HASH: u64 = 0x6fff_fef5;
+const DT_VERSYM: u64 = 0x6fff_fff0;
+const DT_VERDEF: u64 = 0x6fff_fffc;
+
+/// Reason the vDSO image failed validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The image is shorter than an ELF header.
+    Truncated,
+    /// The image does not start with `\x7fELF`.
+    BadMagic,
+    /// The image is not a 64-bit little-endian ELF of the current version.
+    BadClass,
+    /// The image is not a shared object.
+    BadType(u16),
+    /// `e_machine` does not match the target arch.
+    WrongMachine {
+        /// `e_machine` of the image.
+        found: u16,
+        /// `e_machine` of the target arch.
+        expected: u16,
+    },
+    /// The program header table lies outside the image or has an unexpected
+    /// entry size.
+    BadProgramHeaders,
+    /// Program header `index` describes contents outside the image, or more
+    /// file contents than memory.
+    SegmentOutOfBounds {
+        /// Index of the program header.
+        index: usize,
+    },
+    /// Program header `index` is a `PT_LOAD` that is both writable and
+    /// executable.
+    WritableExecutable {
+        /// Index of the program header.
+        index: usize,
+    },
+    /// The `PT_LOAD` segments `first` and `second` share a page.
+    OverlappingSegments {
+        /// Index of the earlier program header.
+        first: usize,
+        /// Index of the later program header.
+        second: usize,
+    },
+    /// The image has no `PT_LOAD` segment.
+    NoLoadSegment,
+    /// Dynamic entry `index` points outside the image, or the dynamic section
+    /// is not terminated by `DT_NULL`.
+    DynamicOutOfBounds {
+        /// Index of the dynamic entry.
+        index: usize,
+    },
+    /// The image bytes differ from the ones checked at build time.
+    ChecksumMismatch {
+        /// Hash of the image bytes.
+        found: u64,
+        /// Hash recorded by the build script.
+        expected: u64,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "image is shorter than an ELF header"),
+            Self::BadMagic => write!(f, "bad ELF magic"),
+            Self::BadClass => write!(f, "not a 64-bit little-endian ELF"),
+            Self::BadType(ty) => write!(f, "e_type is {ty}, expected ET_DYN"),
+            Self::WrongMachine { found, expected } => {
+                write!(f, "e_machine is {found}, expected {expected}")
+            }
+            Self::BadProgramHeaders => write!(f, "program header table is out of bounds"),
+            Self::SegmentOutOfBounds { index } => {
+                write!(f, "program header {index} is out of bounds")
+            }
+            Self::WritableExecutable { index } => {
+                write!(f, "PT_LOAD program header {index} is W+X")
+            }
+            Self::OverlappingSegments { first, second } => {
+                write!(f, "PT_LOAD program headers {first} and {second} overlap")
+            }
+            Self::NoLoadSegment => write!(f, "no PT_LOAD segment"),
+            Self::DynamicOutOfBounds { index } => {
+                write!(f, "dynamic entry {index} is out of bounds")
+            }
+            Self::ChecksumMismatch { found, expected } => {
+                write!(f, "image hash is {found:#018x}, expected {expected:#018x}")
+            }
+        }
+    }
+}
+
+static STRICT: AtomicBool = AtomicBool::new(true);
+
+/// Set whether an image failing [`validate_image`] is refused.
+///
+/// In strict mode, which is the default, loading the vDSO fails with the
+/// validation error. Otherwise the error is logged and the image is used as
+/// long as the loader can still parse it.
+pub fn set_strict_validation(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+/// Whether an image failing [`validate_image`] is refused.
+pub fn strict_validation() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
+
+/// 64-bit FNV-1a hash of `bytes`, as recorded by the build script.
+pub fn image_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Check that `bytes` is the vDSO image the crate was built with and that all
+/// of its program headers and dynamic entries stay within the image.
+pub fn validate_image(bytes: &[u8]) -> Result<(), ValidationError> {
+    if bytes.len() < EHDR_SIZE {
+        return Err(ValidationError::Truncated);
+    }
+    if bytes[..4] != *b"\x7fELF" {
+        return Err(ValidationError::BadMagic);
+    }
+    if bytes[4] != ELFCLASS64 || bytes[5] != ELFDATA2LSB || bytes[6] != EV_CURRENT {
+        return Err(ValidationError::BadClass);
+    }
+    let ty = read_u16(bytes, 16).unwrap_or_default();
+    if ty != ET_DYN {
+        return Err(ValidationError::BadType(ty));
+    }
+    let machine = read_u16(bytes, 18).unwrap_or_default();
+    if machine != ELF_MACHINE {
+        return Err(ValidationError::WrongMachine {
+            found: machine,
+            expected: ELF_MACHINE,
+        });
+    }
+
+    let phoff = read_u64(bytes, 32).unwrap_or_default() as usize;
+    let phentsize = read_u16(bytes, 54).unwrap_or_default() as usize;
+    let phnum = read_u16(bytes, 56).unwrap_or_default() as usize;
+    if phnum != 0 && phentsize != PHDR_SIZE {
+        return Err(ValidationError::BadProgramHeaders);
+    }
+    let phdrs = phnum
+        .checked_mul(PHDR_SIZE)
+        .and_then(|len| phoff.checked_add(len))
+        .and_then(|end| bytes.get(phoff..end))
+        .ok_or(ValidationError::BadProgramHeaders)?;
+
+    let mut load_bias = None;
+    let mut dynamic = None;
+    for (index, ph) in phdrs.chunks_exact(PHDR_SIZE).enumerate() {
+        let ph = ProgramHeader::read(ph);
+        if ph.filesz > ph.memsz
+            || ph
+                .offset
+                .checked_add(ph.filesz)
+                .is_none_or(|end| end > bytes.len())
+            || ph.vaddr.checked_add(ph.memsz).is_none()
+        {
+            return Err(ValidationError::SegmentOutOfBounds { index });
+        }
+        match ph.ty {
+            PT_LOAD => {
+                if ph.flags & (PF_W | PF_X) == PF_W | PF_X {
+                    return Err(ValidationError::WritableExecutable { index });
+                }
+                load_bias.get_or_insert(ph.vaddr.wrapping_sub(ph.offset));
+                check_overlap(phdrs, index, &ph)?;
+            }
+            PT_DYNAMIC => dynamic = Some(ph),
+            _ => {}
+        }
+    }
+    let load_bias = load_bias.ok_or(ValidationError::NoLoadSegment)?;
+    if let Some(ph) = dynamic {
+        let entries = &bytes[ph.offset..ph.offset + ph.filesz];
+        check_dynamic(bytes, entries, load_bias)?;
+    }
+
+    let found = image_hash(bytes);
+    if found != IMAGE_HASH {
+        return Err(ValidationError::ChecksumMismatch {
+            found,
+            expected: IMAGE_HASH,
+        });
+    }
+    Ok(())
+}
+
+/// Fail if the `PT_LOAD` at `index` shares a page with an earlier one.
+fn check_overlap(phdrs: &[u8], index: usize, ph: &ProgramHeader) -> Result<(), ValidationError> {
+    let (start, end) = ph.page_range();
+    for (first, other) in phdrs
+        .chunks_exact(PHDR_SIZE)
+        .take(index)
+        .map(ProgramHeader::read)
+        .enumerate()
+        .filter(|(_, other)| other.ty == PT_LOAD)
+    {
+        let (other_start, other_end) = other.page_range();
+        if start < other_end && other_start < end {
+            return Err(ValidationError::OverlappingSegments {
+                first,
+                second: index,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Check that the addresses in the dynamic entries `entries` point into the
+/// image, and that they end with `DT_NULL`.
+fn check_dynamic(bytes: &[u8], entries: &[u8], load_bias: usize) -> Result<(), ValidationError> {
+    let out_of_bounds = |index| ValidationError::DynamicOutOfBounds { index };
+    let in_image = |addr: u64| {
+        (addr as usize)
+            .checked_sub(load_bias)
+            .filter(|&off| off < bytes.len())
+    };
+    let mut strtab: Option<(usize, usize)> = None;
+    let mut strsz = None;
+    for (index, entry) in entries.chunks_exact(DYN_SIZE).enumerate() {
+        let tag = read_u64(entry, 0).unwrap_or_default();
+        let val = read_u64(entry, 8).unwrap_or_default();
+        match tag {
+            DT_NULL => {
+                return match (strtab, strsz) {
+                    (Some((index, off)), Some(size))
+                        if off.checked_add(size).is_none_or(|end| end > bytes.len()) =>
+                    {
+                        Err(out_of_bounds(index))
+                    }
+                    _ => Ok(()),
+                };
+            }
+            DT_STRTAB => strtab = Some((index, in_image(val).ok_or(out_of_bounds(index))?)),
+            DT_STRSZ => strsz = Some(val as usize),
+            DT_HASH | DT_SYMTAB | DT_GNU_HASH | DT_VERSYM | DT_VERDEF => {
+                in_image(val).ok_or(out_of_bounds(index))?;
+            }
+            _ => {}
+        }
+    }
+    Err(out_of_bounds(entries.len() / DYN_SIZE))
+}
+
+/// The fields of an `Elf64_Phdr` checked here.
+struct ProgramHeader {
+    ty: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    filesz: usize,
+    memsz: usize,
+}
+
+impl ProgramHeader {
+    /// Read a program header from a slice of exactly [`PHDR_SIZE`] bytes.
+    fn read(ph: &[u8]) -> Self {
+        let u64_at = |off| read_u64(ph, off).unwrap_or_default() as usize;
+        Self {
+            ty: read_u32(ph, 0).unwrap_or_default(),
+            flags: read_u32(ph, 4).unwrap_or_default(),
+            offset: u64_at(8),
+            vaddr: u64_at(16),
+            filesz: u64_at(32),
+            memsz: u64_at(40),
+        }
+    }
+
+    /// Page-rounded `[start, end)` of the segment in memory.
+    fn page_range(&self) -> (usize, usize) {
+        let end = self.vaddr + self.memsz;
+        (
+            self.vaddr.align_down(PAGE_SIZE),
+            end.checked_next_multiple_of(PAGE_SIZE)
+                .unwrap_or(usize::MAX),
+        )
+    }
+}
+
+fn read_u16(bytes: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(off..off + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(off..off + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(off..off + 8)?.try_into().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    const PT_NOTE: u32 = 4;
+    const PF_R: u32 = 4;
+
+    /// `(p_type, p_flags, p_offset, p_vaddr, p_filesz, p_memsz)`
+    type Phdr = (u32, u32, u64, u64, u64, u64);
+
+    /// A 3-page image with an ELF header followed by `phdrs`.
+    fn image(phdrs: &[Phdr]) -> Vec<u8> {
+        let mut bytes = vec![0u8; 3 * PAGE_SIZE];
+        bytes[..4].copy_from_slice(b"\x7fELF");
+        bytes[4] = ELFCLASS64;
+        bytes[5] = ELFDATA2LSB;
+        bytes[6] = EV_CURRENT;
+        bytes[16..18].copy_from_slice(&ET_DYN.to_le_bytes());
+        bytes[18..20].copy_from_slice(&ELF_MACHINE.to_le_bytes());
+        bytes[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        bytes[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        bytes[56..58].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        for (i, &(ty, flags, offset, vaddr, filesz, memsz)) in phdrs.iter().enumerate() {
+            let ph = &mut bytes[EHDR_SIZE + i * PHDR_SIZE..][..PHDR_SIZE];
+            ph[0..4].copy_from_slice(&ty.to_le_bytes());
+            ph[4..8].copy_from_slice(&flags.to_le_bytes());
+            ph[8..16].copy_from_slice(&offset.to_le_bytes());
+            ph[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            ph[32..40].copy_from_slice(&filesz.to_le_bytes());
+            ph[40..48].copy_from_slice(&memsz.to_le_bytes());
+        }
+        bytes
+    }
+
+    const PAGE: u64 = PAGE_SIZE as u64;
+
+    fn is_structurally_valid(bytes: &[u8]) -> bool {
+        matches!(
+            validate_image(bytes),
+            Err(ValidationError::ChecksumMismatch { .. })
+        )
+    }
+
+    #[test]
+    fn embedded_image_is_valid() {
+        let path = std::format!(
+            "{}/vdso/vdso_{}.so",
+            env!("CARGO_MANIFEST_DIR"),
+            std::env::consts::ARCH
+        );
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(validate_image(&bytes), Ok(()));
+
+        let mut patched = bytes.clone();
+        *patched.last_mut().unwrap() ^= 1;
+        assert!(is_structurally_valid(&patched));
+    }
+
+    #[test]
+    fn truncated_headers() {
+        let bytes = image(&[(PT_LOAD, PF_R | PF_X, 0, 0, PAGE, PAGE)]);
+        assert!(is_structurally_valid(&bytes));
+        assert_eq!(
+            validate_image(&bytes[..EHDR_SIZE - 1]),
+            Err(ValidationError::Truncated)
+        );
+        // The program header table runs past the end of the image.
+        assert_eq!(
+            validate_image(&bytes[..EHDR_SIZE + PHDR_SIZE - 1]),
+            Err(ValidationError::BadProgramHeaders)
+        );
+        let mut bad_count = bytes.clone();
+        bad_count[56..58].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(
+            validate_image(&bad_count),
+            Err(ValidationError::BadProgramHeaders)
+        );
+    }
+
+    #[test]
+    fn segments_out_of_bounds() {
+        let len = 3 * PAGE;
+        for ph in [
+            (PT_LOAD, PF_R, 0, 0, len + 1, len + 1),
+            (PT_LOAD, PF_R, u64::MAX, 0, 1, 1),
+            (PT_LOAD, PF_R, 0, 0, PAGE, PAGE - 1),
+            (PT_LOAD, PF_R, 0, u64::MAX, PAGE, PAGE),
+        ] {
+            let bytes = image(&[(PT_LOAD, PF_R | PF_X, 0, 0, PAGE, PAGE), ph]);
+            assert_eq!(
+                validate_image(&bytes),
+                Err(ValidationError::SegmentOutOfBounds { index: 1 }),
+                "{ph:x?}"
+            );
+        }
+    }
+
+    #[test]
+    fn overlapping_segments() {
+        let text = (PT_LOAD, PF_R | PF_X, 0, 0, PAGE + 8, PAGE + 8);
+        let bytes = image(&[text, (PT_LOAD, PF_R, 2 * PAGE, 2 * PAGE, 8, 8)]);
+        assert!(is_structurally_valid(&bytes));
+
+        // Shares the second page of the text segment.
+        let bytes = image(&[text, (PT_LOAD, PF_R, PAGE + 8, PAGE + 8, 8, 8)]);
+        assert_eq!(
+            validate_image(&bytes),
+            Err(ValidationError::OverlappingSegments {
+                first: 0,
+                second: 1
+            })
+        );
+        // Overlaps only through its zero-filled tail.
+        let bytes = image(&[
+            (PT_LOAD, PF_R, 2 * PAGE, 0, 8, 2 * PAGE),
+            (PT_NOTE, PF_R, 0, 0, 8, 8),
+            (PT_LOAD, PF_R | PF_X, 0, PAGE, 8, 8),
+        ]);
+        assert_eq!(
+            validate_image(&bytes),
+            Err(ValidationError::OverlappingSegments {
+                first: 0,
+                second: 2
+            })
+        );
+    }
+
+    #[test]
+    fn writable_executable_segment() {
+        let bytes = image(&[(PT_LOAD, PF_R | PF_W | PF_X, 0, 0, PAGE, PAGE)]);
+        assert_eq!(
+            validate_image(&bytes),
+            Err(ValidationError::WritableExecutable { index: 0 })
+        );
+    }
+
+    #[test]
+    fn no_load_segment() {
+        let bytes = image(&[(PT_NOTE, PF_R, 0, 0, 8, 8)]);
+        assert_eq!(validate_image(&bytes), Err(ValidationError::NoLoadSegment));
+    }
+}
@@ -2,23 +2,54 @@
 extern crate alloc;
 extern crate log;
 use alloc::{alloc::alloc_zeroed, vec::Vec};
-use core::alloc::Layout;
+use core::{alloc::Layout, fmt, ops::Range};
 
-use axerrno::{AxError, AxResult};
-use axplat::{mem::virt_to_phys, time::monotonic_time_nanos};
-use kernel_elf_parser::{AuxEntry, AuxType};
+use axplat::{
+    mem::{PhysAddr, virt_to_phys},
+    time::{current_ticks, monotonic_time_nanos},
+};
+use kernel_elf_parser::{AuxEntry, AuxType, ELFHeaders, ELFHeadersBuilder};
 use log::{info, warn};
-use memory_addr::{MemoryAddr, PAGE_SIZE_4K};
+use memory_addr::MemoryAddr;
+use spin::Once;
+use xmas_elf::{
+    ElfFile,
+    sections::{SHN_ABS, SHN_UNDEF, SectionData},
+    symbol_table::{Binding, DynEntry64, Entry, Type},
+};
+
+use crate::{
+    PAGE_SIZE,
+    aslr::{EntropySource, VdsoAslrPolicy},
+    auxv::set_aux,
+    error::{ElfDetail, VdsoError, VdsoResult},
+    guard::VdsoMapGuard,
+    mapper::{VdsoLayout, VdsoMapper, VdsoPerms, VdsoRegion, VdsoSegment},
+    mapping::VdsoMapping,
+    timekeeping::{TIMEKEEPER, Timekeeper},
+    validate::{strict_validation, validate_image},
+};
 
 /// Global vDSO data instance
 #[unsafe(link_section = ".data")]
 pub static mut VDSO_DATA: crate::vdso_data::VdsoData = crate::vdso_data::VdsoData::new();
 
+const _: () = assert!(
+    core::mem::size_of::<crate::vdso_data::VdsoData>() == crate::config::VVAR_PAGES * PAGE_SIZE,
+    "VdsoData does not span exactly the vvar area expected by the vDSO image"
+);
+const _: () = assert!(
+    core::mem::align_of::<crate::vdso_data::VdsoData>() >= PAGE_SIZE,
+    "VdsoData must be aligned to the configured page size"
+);
+
 /// Initialize vDSO data
 pub fn init_vdso_data() {
+    let mut tk = TIMEKEEPER.lock();
+    tk.advance(current_ticks());
     unsafe {
         let data_ptr = core::ptr::addr_of_mut!(VDSO_DATA);
-        (*data_ptr).time_update();
+        (*data_ptr).time_update(&tk);
         info!("vDSO data initialized at {:#x}", data_ptr as usize);
 
         #[cfg(target_arch = "aarch64")]
@@ -34,52 +65,394 @@ pub fn init_vdso_data() {
 }
 
 /// Update vDSO data
+///
+/// Meant for the timer tick: advances the clocks to the current counter value
+/// and publishes them.
 pub fn update_vdso_data() {
+    let mut tk = TIMEKEEPER.lock();
+    tk.advance(current_ticks());
+    publish_vdso_data(&tk);
+}
+
+/// Publish the clocks of `tk` to the vDSO data.
+///
+/// `tk` can only be had through [`TIMEKEEPER`], so holding it makes this the
+/// only writer of `VDSO_DATA`.
+pub(crate) fn publish_vdso_data(tk: &Timekeeper) {
     unsafe {
         let data_ptr = core::ptr::addr_of_mut!(VDSO_DATA);
-        (*data_ptr).time_update();
+        (*data_ptr).time_update(tk);
     }
 }
 
 /// Get the physical address of vDSO data for mapping to userspace
 pub fn vdso_data_paddr() -> usize {
     let data_ptr = core::ptr::addr_of!(VDSO_DATA) as usize;
-    virt_to_phys(data_ptr.into()).into()
+    let paddr: usize = virt_to_phys(data_ptr.into()).into();
+    assert!(
+        paddr.is_aligned(PAGE_SIZE),
+        "vDSO data at {paddr:#x} is not aligned to {PAGE_SIZE:#x}"
+    );
+    paddr
+}
+
+/// Kernel virtual address range `[start, end)` of the embedded vDSO blob.
+fn vdso_kernel_range() -> (usize, usize) {
+    unsafe extern "C" {
+        static vdso_start: u8;
+        static vdso_end: u8;
+    }
+    unsafe {
+        (
+            &vdso_start as *const u8 as usize,
+            &vdso_end as *const u8 as usize,
+        )
+    }
+}
+
+/// Version index marking a symbol as hidden (not the default version).
+const VERSYM_HIDDEN: u16 = 0x8000;
+/// Version index of the base definition (the soname), i.e. unversioned.
+const VER_NDX_GLOBAL: u16 = 1;
+
+/// A function or object exported by the vDSO image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VdsoSymbol<'a> {
+    /// Symbol name, e.g. `__vdso_clock_gettime`.
+    pub name: &'a str,
+    /// Version the symbol is defined in, e.g. `LINUX_2.6`.
+    pub version: Option<&'a str>,
+    /// Offset of the symbol from the start of the image.
+    pub offset: usize,
+    /// Size of the symbol in bytes.
+    pub size: usize,
+}
+
+impl fmt::Display for VdsoSymbol<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.version {
+            Some(version) => write!(f, "{}@{} at {:#x}", self.name, version, self.offset),
+            None => write!(f, "{} at {:#x}", self.name, self.offset),
+        }
+    }
+}
+
+/// Parsed view of a vDSO ELF image, used to map it and resolve its exported
+/// symbols.
+///
+/// The dynamic symbol table is read from `.dynsym`/`.dynstr`, and symbol
+/// versions from `.gnu.version` and `.gnu.version_d`.
+pub struct VdsoImage<'a> {
+    bytes: &'a [u8],
+    headers: ELFHeaders,
+    elf: ElfFile<'a>,
+    dynsym: &'a [DynEntry64],
+    versym: &'a [u8],
+    verdefs: Vec<(u16, &'a str)>,
+    load_bias: usize,
+}
+
+impl<'a> VdsoImage<'a> {
+    /// Parse the vDSO image contained in `bytes`.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, &'static str> {
+        let elf = ElfFile::new(bytes)?;
+        let headers = ELFHeadersBuilder::new(bytes)
+            .and_then(|b| {
+                let range = b.ph_range();
+                b.build(&bytes[range.start as usize..range.end as usize])
+            })
+            .map_err(|_| "invalid ELF program headers")?;
+        let first_load = headers
+            .ph
+            .iter()
+            .find(|ph| ph.get_type() == Ok(xmas_elf::program::Type::Load))
+            .ok_or("no PT_LOAD segment")?;
+        let load_bias = first_load.virtual_addr.wrapping_sub(first_load.offset) as usize;
+
+        let dynsym = match elf
+            .find_section_by_name(".dynsym")
+            .ok_or("no .dynsym section")?
+            .get_data(&elf)?
+        {
+            SectionData::DynSymbolTable64(entries) => entries,
+            _ => return Err("unexpected .dynsym section type"),
+        };
+        let versym = elf
+            .find_section_by_name(".gnu.version")
+            .map(|sh| sh.raw_data(&elf))
+            .unwrap_or_default();
+        let verdefs = match elf.find_section_by_name(".gnu.version_d") {
+            Some(sh) => parse_verdefs(&elf, sh.raw_data(&elf))?,
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            bytes,
+            headers,
+            elf,
+            dynsym,
+            versym,
+            verdefs,
+            load_bias,
+        })
+    }
+
+    /// Raw bytes of the image.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// ELF and program headers of the image.
+    pub fn headers(&self) -> &ELFHeaders {
+        &self.headers
+    }
+
+    /// Virtual address the image was linked to load its ELF header at, taken
+    /// from the first `PT_LOAD` segment.
+    ///
+    /// Symbol values and segment addresses are relative to this.
+    pub fn load_bias(&self) -> usize {
+        self.load_bias
+    }
+
+    /// Iterate over all symbols exported by the image.
+    pub fn symbols(&self) -> impl Iterator<Item = VdsoSymbol<'a>> + '_ {
+        self.entries().map(|(sym, _)| sym)
+    }
+
+    /// Look up a symbol by name, optionally restricted to a version.
+    ///
+    /// Without a version, the default (non-hidden) definition is preferred.
+    pub fn lookup(&self, name: &str, version: Option<&str>) -> Option<VdsoSymbol<'a>> {
+        let mut fallback = None;
+        for (sym, hidden) in self.entries().filter(|(sym, _)| sym.name == name) {
+            match version {
+                Some(version) if sym.version == Some(version) => return Some(sym),
+                Some(_) => {}
+                None if !hidden => return Some(sym),
+                None => {
+                    fallback.get_or_insert(sym);
+                }
+            }
+        }
+        fallback
+    }
+
+    /// Resolve a symbol given as `name` or `name@VERSION`, e.g.
+    /// `__vdso_clock_gettime@LINUX_2.6`.
+    pub fn resolve(&self, spec: &str) -> Option<VdsoSymbol<'a>> {
+        match spec.split_once('@') {
+            Some((name, version)) => self.lookup(name, Some(version)),
+            None => self.lookup(spec, None),
+        }
+    }
+
+    /// Exported symbols along with whether their version is hidden.
+    fn entries(&self) -> impl Iterator<Item = (VdsoSymbol<'a>, bool)> + '_ {
+        self.dynsym
+            .iter()
+            .enumerate()
+            .filter(|(_, sym)| {
+                sym.shndx() != SHN_UNDEF
+                    && sym.shndx() != SHN_ABS
+                    && matches!(sym.get_binding(), Ok(Binding::Global | Binding::Weak))
+                    && sym.get_type() != Ok(Type::Section)
+            })
+            .filter_map(|(idx, sym)| {
+                let versym = self.versym_at(idx).unwrap_or(VER_NDX_GLOBAL);
+                let symbol = VdsoSymbol {
+                    name: sym.get_name(&self.elf).ok()?,
+                    version: self.version_name(versym & !VERSYM_HIDDEN),
+                    offset: (sym.value() as usize).wrapping_sub(self.load_bias),
+                    size: sym.size() as usize,
+                };
+                Some((symbol, versym & VERSYM_HIDDEN != 0))
+            })
+    }
+
+    fn versym_at(&self, idx: usize) -> Option<u16> {
+        let raw = self.versym.get(idx * 2..idx * 2 + 2)?;
+        Some(u16::from_le_bytes([raw[0], raw[1]]))
+    }
+
+    fn version_name(&self, ndx: u16) -> Option<&'a str> {
+        if ndx <= VER_NDX_GLOBAL {
+            return None;
+        }
+        self.verdefs
+            .iter()
+            .find(|(vd_ndx, _)| *vd_ndx == ndx)
+            .map(|(_, name)| *name)
+    }
+}
+
+impl VdsoImage<'static> {
+    /// The vDSO image embedded in the kernel between `vdso_start` and
+    /// `vdso_end`, parsed once.
+    pub fn embedded() -> Option<&'static Self> {
+        embedded_vdso().ok().map(|vdso| &vdso.image)
+    }
+}
+
+/// The embedded vDSO, page aligned and parsed once.
+///
+/// Every address space maps these same physical pages read-only, so they are
+/// never freed: when `vdso_start` is not page aligned, the aligned copy made by
+/// [`prepare_vdso_pages`] lives for the lifetime of the kernel.
+///
+/// The image is checked with [`validate_image`] first. Whether a failure
+/// refuses the image is decided by [`strict_validation`] at that point, i.e.
+/// before the first vDSO load.
+///
+/// Failures are cached along with the image, except for running out of
+/// memory, which the next load retries.
+struct EmbeddedVdso {
+    pages: VdsoPageInfo,
+    image: VdsoImage<'static>,
+    /// Layout of the `PT_LOAD` segments of `image`.
+    segments: Vec<SegmentLayout>,
+    /// Size of the page-rounded mapping covering all segments.
+    size: usize,
+    /// Offset of the signal return trampoline within `image`, if exported.
+    sigreturn: Option<usize>,
+}
+
+static EMBEDDED_VDSO: Once<VdsoResult<EmbeddedVdso>> = Once::new();
+
+fn embedded_vdso() -> VdsoResult<&'static EmbeddedVdso> {
+    EMBEDDED_VDSO
+        .try_call_once(|| match prepare_embedded_vdso() {
+            Err(VdsoError::OutOfMemory) => Err(VdsoError::OutOfMemory),
+            result => Ok(result),
+        })?
+        .as_ref()
+        .map_err(|e| *e)
+}
+
+fn prepare_embedded_vdso() -> VdsoResult<EmbeddedVdso> {
+    let (vdso_kstart, vdso_kend) = vdso_kernel_range();
+    info!("vdso_kstart: {vdso_kstart:#x}, vdso_kend: {vdso_kend:#x}");
+
+    if vdso_kend <= vdso_kstart {
+        warn!(
+            "vDSO binary is missing or invalid: vdso_kstart={vdso_kstart:#x}, \
+             vdso_kend={vdso_kend:#x}. vDSO will not be loaded and AT_SYSINFO_EHDR will not be \
+             set."
+        );
+        return Err(VdsoError::MissingImage);
+    }
+
+    let pages = prepare_vdso_pages(vdso_kstart, vdso_kend)
+        .inspect_err(|e| warn!("failed to prepare vDSO pages: {e}"))?;
+    let mut alloc_guard = crate::guard::VdsoAllocGuard::new(pages.alloc);
+    if let Err(e) = validate_image(pages.bytes) {
+        if strict_validation() {
+            warn!("refusing invalid vDSO image: {e}");
+            return Err(e.into());
+        }
+        warn!("vDSO image failed validation, loading it anyway: {e}");
+    }
+    let image = VdsoImage::parse(pages.bytes)
+        .map_err(|e| VdsoError::MalformedElf(ElfDetail::Parse(e)))
+        .inspect_err(|e| warn!("{e}"))?;
+    let (segments, size) = layout_segments(&image, &pages)
+        .inspect_err(|e| warn!("failed to lay out vDSO segments: {e}"))?;
+    let sigreturn = sigreturn_offset(&image);
+    alloc_guard.disarm();
+    Ok(EmbeddedVdso {
+        pages,
+        image,
+        segments,
+        size,
+        sigreturn,
+    })
+}
+
+/// Collect `(index, name)` pairs from the `.gnu.version_d` section.
+fn parse_verdefs<'a>(
+    elf: &ElfFile<'a>,
+    data: &'a [u8],
+) -> Result<Vec<(u16, &'a str)>, &'static str> {
+    const ERR: &str = "truncated .gnu.version_d section";
+    let read_u16 = |off: usize| -> Result<u16, &'static str> {
+        let b = data.get(off..off + 2).ok_or(ERR)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    };
+    let read_u32 = |off: usize| -> Result<u32, &'static str> {
+        let b = data.get(off..off + 4).ok_or(ERR)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let mut verdefs = Vec::new();
+    let mut off = 0usize;
+    while off < data.len() {
+        // Elf64_Verdef: vd_version, vd_flags, vd_ndx, vd_cnt, vd_hash, vd_aux,
+        // vd_next.
+        let vd_ndx = read_u16(off + 4)?;
+        let vd_aux = read_u32(off + 12)? as usize;
+        let vd_next = read_u32(off + 16)? as usize;
+        // Elf64_Verdaux: vda_name, vda_next. The first entry names the version.
+        let vda_name = read_u32(off + vd_aux)?;
+        verdefs.push((vd_ndx, elf.get_dyn_string(vda_name)?));
+        if vd_next == 0 {
+            break;
+        }
+        off += vd_next;
+    }
+    Ok(verdefs)
 }
 
 /// Information about loaded vDSO pages for userspace mapping and auxv update.
-pub type VdsoPageInfo = (
-    axplat::mem::PhysAddr,
-    &'static [u8],
-    usize,
-    usize,
-    Option<(usize, usize)>,
-);
+#[derive(Debug, Clone, Copy)]
+pub struct VdsoPageInfo {
+    /// Physical address of the page holding the start of the image.
+    pub paddr: PhysAddr,
+    /// The image itself.
+    pub bytes: &'static [u8],
+    /// Size of the page-rounded mapping covering the image.
+    pub size: usize,
+    /// Offset of the image within its first page.
+    pub page_offset: usize,
+    /// `(vaddr, num_pages)` of the aligned copy, if one had to be allocated.
+    pub alloc: Option<(usize, usize)>,
+}
 
-/// Load vDSO into the given user address space and update auxv accordingly.
-pub fn prepare_vdso_pages(vdso_kstart: usize, vdso_kend: usize) -> AxResult<VdsoPageInfo> {
+/// Make the vDSO image at `[vdso_kstart, vdso_kend)` mappable by page.
+///
+/// If the image is not page aligned, it is copied into freshly allocated
+/// pages which are returned in [`VdsoPageInfo::alloc`] and never freed by this
+/// function.
+pub fn prepare_vdso_pages(vdso_kstart: usize, vdso_kend: usize) -> VdsoResult<VdsoPageInfo> {
     let orig_vdso_len = vdso_kend - vdso_kstart;
-    let orig_page_off = vdso_kstart & (PAGE_SIZE_4K - 1);
+    let orig_page_off = vdso_kstart.align_offset(PAGE_SIZE);
 
     if orig_page_off == 0 {
         // Already page aligned: use original memory region directly.
         let vdso_paddr_page = virt_to_phys(vdso_kstart.into());
-        let vdso_size = (vdso_kend - vdso_kstart + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+        assert!(
+            vdso_paddr_page.as_usize().is_aligned(PAGE_SIZE),
+            "vDSO image at {vdso_paddr_page:?} is not aligned to {PAGE_SIZE:#x}"
+        );
+        let vdso_size = orig_vdso_len.align_up(PAGE_SIZE);
         let vdso_bytes =
             unsafe { core::slice::from_raw_parts(vdso_kstart as *const u8, orig_vdso_len) };
-        Ok((vdso_paddr_page, vdso_bytes, vdso_size, 0usize, None))
+        Ok(VdsoPageInfo {
+            paddr: vdso_paddr_page,
+            bytes: vdso_bytes,
+            size: vdso_size,
+            page_offset: 0,
+            alloc: None,
+        })
     } else {
         let total_size = orig_vdso_len + orig_page_off;
-        let num_pages = total_size.div_ceil(PAGE_SIZE_4K);
-        let vdso_size = num_pages * PAGE_SIZE_4K;
+        let num_pages = total_size.div_ceil(PAGE_SIZE);
+        let vdso_size = num_pages * PAGE_SIZE;
 
-        let layout = match Layout::from_size_align(vdso_size, PAGE_SIZE_4K) {
-            Ok(l) => l,
-            Err(_) => return Err(AxError::InvalidExecutable),
-        };
+        let layout =
+            Layout::from_size_align(vdso_size, PAGE_SIZE).map_err(|_| VdsoError::OutOfMemory)?;
         let alloc_ptr = unsafe { alloc_zeroed(layout) };
         if alloc_ptr.is_null() {
-            return Err(AxError::InvalidExecutable);
+            return Err(VdsoError::OutOfMemory);
         }
         // destination start where vdso_start should reside
         let dest = unsafe { alloc_ptr.add(orig_page_off) };
@@ -88,213 +461,423 @@ pub fn prepare_vdso_pages(vdso_kstart: usize, vdso_kend: usize) -> AxResult<Vdso
         let alloc_vaddr = alloc_ptr as usize;
         let vdso_paddr_page = virt_to_phys(alloc_vaddr.into());
         let vdso_bytes = unsafe { core::slice::from_raw_parts(dest as *const u8, orig_vdso_len) };
-        Ok((
-            vdso_paddr_page,
-            vdso_bytes,
-            vdso_size,
-            orig_page_off,
-            Some((alloc_vaddr, num_pages)),
-        ))
+        assert!(
+            vdso_paddr_page.as_usize().is_aligned(PAGE_SIZE),
+            "vDSO pages at {vdso_paddr_page:?} are not aligned to {PAGE_SIZE:#x}"
+        );
+        Ok(VdsoPageInfo {
+            paddr: vdso_paddr_page,
+            bytes: vdso_bytes,
+            size: vdso_size,
+            page_offset: orig_page_off,
+            alloc: Some((alloc_vaddr, num_pages)),
+        })
     }
 }
 
-/// Calculate ASLR-randomized vDSO user address
+/// Calculate where the `[vvar][vdso]` span should end according to `policy`,
+/// drawing randomness from `entropy`.
+///
+/// `mmap_base` is the mmap base of the address space, if known, and
+/// `vdso_size` the size of the image mapping. The result is page aligned.
 pub fn calculate_vdso_aslr_addr(
-    vdso_kstart: usize,
-    vdso_kend: usize,
-    vdso_page_offset: usize,
-) -> (usize, usize) {
+    policy: &VdsoAslrPolicy,
+    entropy: &mut dyn EntropySource,
+    mmap_base: Option<usize>,
+    vdso_size: usize,
+) -> usize {
+    let rand = if policy.is_randomized() {
+        entropy.random_u64().unwrap_or_else(fallback_random_u64)
+    } else {
+        0
+    };
+    policy.span_end(rand, mmap_base, vdso_size)
+}
+
+/// Weak randomness for when the entropy source is unavailable.
+fn fallback_random_u64() -> u64 {
     use rand_core::RngCore;
     use rand_pcg::Pcg64Mcg;
 
-    const VDSO_USER_ADDR_BASE: usize = 0x7f00_0000;
-    const VDSO_ASLR_PAGES: usize = 256;
-
+    warn!("vDSO entropy source unavailable, falling back to a time-seeded PRNG");
+    let (vdso_kstart, vdso_kend) = vdso_kernel_range();
     let seed: u128 = (monotonic_time_nanos() as u128)
         ^ ((vdso_kstart as u128).rotate_left(13))
         ^ ((vdso_kend as u128).rotate_left(37));
-    let mut rng = Pcg64Mcg::new(seed);
-    let page_off: usize = (rng.next_u64() as usize) % VDSO_ASLR_PAGES;
-    let base_addr = VDSO_USER_ADDR_BASE + page_off * PAGE_SIZE_4K;
-    let vdso_addr = if vdso_page_offset != 0 {
-        base_addr.wrapping_add(vdso_page_offset)
-    } else {
-        base_addr
-    };
-
-    (base_addr, vdso_addr)
+    Pcg64Mcg::new(seed).next_u64()
 }
 
 /// Load vDSO into the given user address space and update auxv accordingly.
-pub fn load_vdso_data<F1, F2, F3>(auxv: &mut Vec<AuxEntry>, f1: F1, f2: F2, f3: F3) -> AxResult<()>
+///
+/// Returns the [`VdsoMapping`] describing where everything went.
+///
+/// The vDSO is placed according to `policy`, usually
+/// [`default_aslr_policy`](crate::aslr::default_aslr_policy), with randomness
+/// drawn from `entropy`.
+///
+/// Installation is all-or-nothing: if any mapping fails, everything mapped so
+/// far is removed again through [`VdsoMapper::unmap`] and `auxv` is left
+/// untouched.
+pub fn load_vdso_data<M>(
+    auxv: &mut Vec<AuxEntry>,
+    mapper: &mut M,
+    policy: &VdsoAslrPolicy,
+    entropy: &mut dyn EntropySource,
+) -> VdsoResult<VdsoMapping>
 where
-    F1: FnOnce(usize, axplat::mem::PhysAddr, usize) -> AxResult<()>,
-    F2: FnOnce(usize, usize) -> AxResult<()>,
-    F3: FnMut(
-        usize,
-        axplat::mem::PhysAddr,
-        usize,
-        &xmas_elf::program::ProgramHeader64,
-    ) -> AxResult<()>,
+    M: VdsoMapper + ?Sized,
 {
-    unsafe extern "C" {
-        static vdso_start: u8;
-        static vdso_end: u8;
+    let vdso = embedded_vdso()?;
+
+    let vvar_size = crate::config::VVAR_PAGES * PAGE_SIZE;
+    let span = vvar_size + vdso.size;
+    let span_end = calculate_vdso_aslr_addr(policy, entropy, mapper.mmap_base(), vdso.size);
+    let span_start = mapper
+        .find_free_range(span_end, span)
+        .ok_or(VdsoError::OutOfMemory)?;
+    if policy.fixed.is_some() && span_start + span != span_end {
+        let e = VdsoError::AddressInUse(span_end - vdso.size);
+        warn!("{e}");
+        return Err(e);
     }
-    let (vdso_kstart, vdso_kend) = unsafe {
-        (
-            &vdso_start as *const u8 as usize,
-            &vdso_end as *const u8 as usize,
-        )
-    };
-    info!("vdso_kstart: {vdso_kstart:#x}, vdso_kend: {vdso_kend:#x}");
 
-    if vdso_kend <= vdso_kstart {
-        warn!(
-            "vDSO binary is missing or invalid: vdso_kstart={vdso_kstart:#x}, \
-             vdso_kend={vdso_kend:#x}. vDSO will not be loaded and AT_SYSINFO_EHDR will not be \
-             set."
-        );
-        return Err(AxError::InvalidExecutable);
-    }
-
-    let (vdso_paddr_page, vdso_bytes, vdso_size, vdso_page_offset, alloc_info) =
-        prepare_vdso_pages(vdso_kstart, vdso_kend).map_err(|_| AxError::InvalidExecutable)?;
-
-    let mut alloc_guard = crate::guard::VdsoAllocGuard::new(alloc_info);
-
-    let (_base_addr, vdso_user_addr) =
-        calculate_vdso_aslr_addr(vdso_kstart, vdso_kend, vdso_page_offset);
-
-    match kernel_elf_parser::ELFHeadersBuilder::new(vdso_bytes).and_then(|b| {
-        let range = b.ph_range();
-        b.build(&vdso_bytes[range.start as usize..range.end as usize])
-    }) {
-        Ok(headers) => {
-            map_vdso_segments(
-                headers,
-                vdso_user_addr,
-                vdso_paddr_page,
-                vdso_page_offset,
-                f3,
-            )?;
-            alloc_guard.disarm();
-        }
-        Err(_) => {
-            info!("vDSO ELF parsing failed, using fallback mapping");
-            let map_user_start = if vdso_page_offset == 0 {
-                vdso_user_addr
-            } else {
-                vdso_user_addr - vdso_page_offset
-            };
-            f1(map_user_start, vdso_paddr_page, vdso_size)?;
-            alloc_guard.disarm();
-        }
+    let mapping = install_vdso(vdso, span_start + vvar_size, mapper)?;
+    set_aux(auxv, AuxType::SYSINFO_EHDR, mapping.vdso_base());
+    Ok(mapping)
+}
+
+/// Map a fresh vDSO with its image starting at `addr` and its vvar pages
+/// right below, for `arch_prctl(ARCH_MAP_VDSO_*)`.
+///
+/// `addr` must be page aligned and the whole `[vvar][vdso]` span must be free.
+/// As in Linux, a process gets at most one vDSO: if `current`, the mapping of
+/// the address space, still has either region mapped, this fails with
+/// [`VdsoError::AddressInUse`].
+///
+/// auxv is not touched. Returns the new mapping along with the size of the
+/// image mapping, which is what the syscall returns.
+pub fn map_vdso_at<M>(
+    mapper: &mut M,
+    current: Option<&VdsoMapping>,
+    addr: usize,
+) -> VdsoResult<(VdsoMapping, usize)>
+where
+    M: VdsoMapper + ?Sized,
+{
+    if let Some(current) = current
+        && (current.is_vdso_mapped() || current.is_vvar_mapped())
+    {
+        let e = VdsoError::AddressInUse(current.vdso_base());
+        warn!("vDSO already mapped: {e}");
+        return Err(e);
+    }
+    let vdso = embedded_vdso()?;
+    let vvar_size = crate::config::VVAR_PAGES * PAGE_SIZE;
+    let (Some(span_start), Some(span_end)) =
+        (addr.checked_sub(vvar_size), addr.checked_add(vdso.size))
+    else {
+        return Err(VdsoError::BadAddress(addr));
+    };
+    if !addr.is_aligned(PAGE_SIZE) {
+        return Err(VdsoError::BadAddress(addr));
+    }
+    if mapper.find_free_range(span_end, span_end - span_start) != Some(span_start) {
+        return Err(VdsoError::AddressInUse(addr));
     }
 
-    map_vvar_and_push_aux(auxv, vdso_user_addr, f2)?;
+    let mapping = install_vdso(vdso, addr, mapper)?;
+    Ok((mapping, vdso.size))
+}
 
-    Ok(())
+/// Map the image of `vdso` starting at the page-aligned `map_base`, with the
+/// vvar pages right below.
+///
+/// Installation is all-or-nothing: anything mapped is unmapped again if a
+/// later step fails.
+fn install_vdso<M>(vdso: &EmbeddedVdso, map_base: usize, mapper: &mut M) -> VdsoResult<VdsoMapping>
+where
+    M: VdsoMapper + ?Sized,
+{
+    let vdso_user_addr = map_base + vdso.pages.page_offset;
+
+    let mut guard = VdsoMapGuard::new(mapper);
+    let segments = map_vdso_segments(&vdso.segments, map_base, &mut guard)?;
+    let (vvar_start, vvar_size) = map_vvar(vdso_user_addr, &mut guard)?;
+    guard.disarm();
+
+    let layout = VdsoLayout {
+        vdso_base: vdso_user_addr,
+        vvar_start,
+        vvar_size,
+        vdso_size: vdso.size,
+    };
+    Ok(VdsoMapping::new(layout, segments))
 }
 
-fn map_vvar_and_push_aux<F>(auxv: &mut Vec<AuxEntry>, vdso_user_addr: usize, f: F) -> AxResult<()>
+/// Map the vvar pages below the vDSO and return their user range as
+/// `(start, size)`.
+fn map_vvar<M>(vdso_user_addr: usize, mapper: &mut M) -> VdsoResult<(usize, usize)>
 where
-    F: FnOnce(usize, usize) -> AxResult<()>,
+    M: VdsoMapper + ?Sized,
 {
     use crate::config::VVAR_PAGES;
-    let vvar_user_addr = vdso_user_addr - VVAR_PAGES * PAGE_SIZE_4K;
-    let vvar_paddr = vdso_data_paddr();
-
-    f(vvar_user_addr, vvar_paddr)?;
-
-    info!(
-        "Mapped vvar pages at user {:#x}..{:#x} -> paddr {:#x}",
-        vvar_user_addr,
-        vvar_user_addr + VVAR_PAGES * PAGE_SIZE_4K,
-        vvar_paddr,
-    );
-
-    let aux_entry = AuxEntry::new(AuxType::SYSINFO_EHDR, vdso_user_addr);
-    auxv.push(aux_entry);
+    let vvar_size = VVAR_PAGES * PAGE_SIZE;
+    let vvar_user_addr = vdso_user_addr.align_down(PAGE_SIZE) - vvar_size;
+    map_vvar_regions(vvar_user_addr, mapper)?;
+    Ok((vvar_user_addr, vvar_size))
+}
 
+/// Map every region of the vvar area starting at `vvar_start`.
+pub(crate) fn map_vvar_regions<M>(vvar_start: usize, mapper: &mut M) -> VdsoResult
+where
+    M: VdsoMapper + ?Sized,
+{
+    let vvar_paddr = vdso_data_paddr();
+    for (region, range) in vvar_regions() {
+        let user_start = vvar_start + range.start;
+        mapper
+            .map_vvar(
+                region,
+                user_start,
+                (vvar_paddr + range.start).into(),
+                range.len(),
+            )
+            .map_err(VdsoError::MappingFailed)?;
+        info!(
+            "Mapped {} at user {:#x}..{:#x} -> paddr {:#x}",
+            region.name(),
+            user_start,
+            user_start + range.len(),
+            vvar_paddr + range.start,
+        );
+    }
     Ok(())
 }
 
-fn map_vdso_segments<F>(
-    headers: kernel_elf_parser::ELFHeaders,
-    vdso_user_addr: usize,
-    vdso_paddr_page: axplat::mem::PhysAddr,
-    vdso_page_offset: usize,
-    mut f: F,
-) -> AxResult<()>
+/// The regions making up the vvar area, as byte ranges from its start.
+///
+/// The x86 pvclock page is its own [`VdsoRegion::VvarVclock`] region; the rest
+/// of the area is [`VdsoRegion::Vvar`].
+pub(crate) fn vvar_regions() -> impl Iterator<Item = (VdsoRegion, Range<usize>)> {
+    let vvar_size = crate::config::VVAR_PAGES * PAGE_SIZE;
+    #[cfg(target_arch = "x86_64")]
+    let vclock = {
+        use crate::{
+            vdso_data::VdsoData,
+            x86_64::{config::PVCLOCK_MAX_CPUS, pvclock_data::PvClockTimeInfo},
+        };
+        let start = core::mem::offset_of!(VdsoData, pvclock);
+        let end = start + core::mem::size_of::<[PvClockTimeInfo; PVCLOCK_MAX_CPUS]>();
+        start.align_down(PAGE_SIZE)..end.align_up(PAGE_SIZE)
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let vclock = vvar_size..vvar_size;
+
+    [
+        (VdsoRegion::Vvar, 0..vclock.start),
+        (VdsoRegion::VvarVclock, vclock.clone()),
+        (VdsoRegion::Vvar, vclock.end..vvar_size),
+    ]
+    .into_iter()
+    .filter(|(_, range)| !range.is_empty())
+}
+
+/// Map `segments` at `map_base` and return the mappings made.
+fn map_vdso_segments<M>(
+    segments: &[SegmentLayout],
+    map_base: usize,
+    mapper: &mut M,
+) -> VdsoResult<Vec<VdsoSegment>>
 where
-    F: FnMut(
-        usize,
-        axplat::mem::PhysAddr,
-        usize,
-        &xmas_elf::program::ProgramHeader64,
-    ) -> AxResult<()>,
+    M: VdsoMapper + ?Sized,
 {
-    info!("vDSO ELF parsed successfully, mapping segments");
-    for ph in headers
+    let mut mapped = Vec::new();
+    for seg in segments {
+        if seg.file_size != 0 {
+            mapped.push(VdsoSegment {
+                user_start: map_base + seg.start,
+                paddr: seg.paddr,
+                size: seg.file_size,
+                perms: seg.perms,
+                region: VdsoRegion::Vdso,
+            });
+        }
+        if let Some((paddr, size)) = seg.tail {
+            mapped.push(VdsoSegment {
+                user_start: map_base + seg.start + seg.file_size,
+                paddr,
+                size,
+                perms: seg.perms,
+                region: VdsoRegion::Vdso,
+            });
+        }
+    }
+    for segment in &mapped {
+        mapper
+            .map_segment(segment)
+            .map_err(VdsoError::MappingFailed)?;
+    }
+    Ok(mapped)
+}
+
+/// A `PT_LOAD` segment of the embedded image, placed relative to the first
+/// page of the image mapping.
+#[derive(Debug, Clone, Copy)]
+struct SegmentLayout {
+    /// Offset of the first page of the segment from the mapping base.
+    start: usize,
+    /// Physical address of the image pages backing the segment.
+    paddr: PhysAddr,
+    /// Page-rounded size of the part backed by image pages.
+    file_size: usize,
+    /// `(paddr, size)` of the zero-filled pages backing the rest of the
+    /// segment, if `p_memsz` extends past the last full page of `p_filesz`.
+    tail: Option<(PhysAddr, usize)>,
+    perms: VdsoPerms,
+}
+
+/// Lay out the `PT_LOAD` segments of `image` and return them along with the
+/// size of the mapping covering them.
+///
+/// Segments are placed by `p_vaddr` relative to [`VdsoImage::load_bias`] and
+/// backed by the image pages at `p_offset`. The part of a segment past
+/// `p_filesz` is backed by freshly zeroed pages, starting with a copy of the
+/// partial page holding the end of the file contents. Like the image pages,
+/// these are shared by every address space and never freed.
+fn layout_segments(
+    image: &VdsoImage<'_>,
+    pages: &VdsoPageInfo,
+) -> VdsoResult<(Vec<SegmentLayout>, usize)> {
+    let mut segments = Vec::new();
+    let mut tail_guards = Vec::new();
+    let mut size = 0;
+    for ph in image
+        .headers()
         .ph
         .iter()
         .filter(|ph| ph.get_type() == Ok(xmas_elf::program::Type::Load))
     {
         let vaddr = ph.virtual_addr as usize;
-        let seg_pad = vaddr.align_offset_4k() + vdso_page_offset;
-        let seg_align_size =
-            (ph.mem_size as usize + seg_pad + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+        let offset = ph.offset as usize;
+        let file_size = ph.file_size as usize;
+        let mem_size = ph.mem_size as usize;
+        let mismatch = |reason| VdsoError::LayoutMismatch { vaddr, reason };
+        let perms = VdsoPerms::from_flags(ph.flags).ok_or(mismatch("is writable"))?;
+        if file_size > mem_size
+            || offset
+                .checked_add(file_size)
+                .is_none_or(|end| end > pages.bytes.len())
+        {
+            return Err(mismatch("lies outside the image"));
+        }
+
+        // Offsets of the segment and of its file contents from the mapping
+        // base, which maps the page holding the ELF header.
+        let rel = vaddr
+            .checked_sub(image.load_bias())
+            .and_then(|rel| rel.checked_add(pages.page_offset))
+            .ok_or(mismatch("lies below the load bias"))?;
+        let file_rel = pages.page_offset + offset;
+        let image_rel = rel
+            .checked_sub(offset)
+            .ok_or(mismatch("lies below the start of the image"))?;
+        if rel.align_offset(PAGE_SIZE) != file_rel.align_offset(PAGE_SIZE) {
+            return Err(mismatch("is not congruent to its file offset"));
+        }
 
-        let map_base_user = vdso_user_addr & !(PAGE_SIZE_4K - 1);
-        let seg_user_start = map_base_user + vaddr.align_down_4k();
-        let seg_paddr = vdso_paddr_page + vaddr.align_down_4k();
+        let start = rel.align_down(PAGE_SIZE);
+        let file_end = rel + file_size;
+        let mem_end = (rel + mem_size).align_up(PAGE_SIZE);
+        let (mapped_file_end, tail) = if mem_size == file_size {
+            (file_end.align_up(PAGE_SIZE), None)
+        } else {
+            let tail_start = file_end.align_down(PAGE_SIZE).max(start);
+            let tail_size = mem_end - tail_start;
+            let (paddr, alloc) =
+                zeroed_tail(image.bytes(), image_rel, tail_start, file_end, tail_size)?;
+            tail_guards.push(crate::guard::VdsoAllocGuard::new(Some(alloc)));
+            (tail_start, Some((paddr, tail_size)))
+        };
 
-        f(seg_user_start, seg_paddr, seg_align_size, ph)?;
+        segments.push(SegmentLayout {
+            start,
+            paddr: pages.paddr + file_rel.align_down(PAGE_SIZE),
+            file_size: mapped_file_end - start,
+            tail,
+            perms,
+        });
+        size = size.max(mem_end);
     }
-    Ok(())
-}
 
-#[cfg(not(target_arch = "x86_64"))]
-pub fn get_trampoline_addr(auxv: &[AuxEntry]) -> Option<usize> {
-    let vdso_base = auxv
-        .iter()
-        .find(|entry| entry.get_type() == AuxType::SYSINFO_EHDR)
-        .map(|entry| entry.value());
+    for guard in &mut tail_guards {
+        guard.disarm();
+    }
+    Ok((segments, size))
+}
 
-    if vdso_base.is_none() {
-        warn!("get_trampoline_addr: AT_SYSINFO_EHDR not found in auxv");
-        return None;
+/// Allocate `size` bytes of zeroed pages for the tail of a segment starting at
+/// mapping offset `tail_start`, filled with the image contents up to
+/// `file_end`. The image starts at mapping offset `image_rel`.
+///
+/// Returns the physical address of the pages and their `(vaddr, num_pages)`.
+fn zeroed_tail(
+    bytes: &[u8],
+    image_rel: usize,
+    tail_start: usize,
+    file_end: usize,
+    size: usize,
+) -> VdsoResult<(PhysAddr, (usize, usize))> {
+    let layout = Layout::from_size_align(size, PAGE_SIZE).map_err(|_| VdsoError::OutOfMemory)?;
+    let ptr = unsafe { alloc_zeroed(layout) };
+    if ptr.is_null() {
+        return Err(VdsoError::OutOfMemory);
+    }
+    let tail = unsafe { core::slice::from_raw_parts_mut(ptr, size) };
+
+    // Image bytes sharing the first tail page with the end of the file
+    // contents; anything before the image stays zero.
+    let copy_start = tail_start.max(image_rel);
+    if copy_start < file_end {
+        let src = &bytes[copy_start - image_rel..file_end - image_rel];
+        tail[copy_start - tail_start..file_end - tail_start].copy_from_slice(src);
     }
-    let vdso_base = vdso_base.unwrap();
-    info!("get_trampoline_addr: found vdso_base={:#x}", vdso_base);
 
-    let mut sigreturn_offset: Option<usize> = None;
+    let vaddr = ptr as usize;
+    Ok((virt_to_phys(vaddr.into()), (vaddr, size / PAGE_SIZE)))
+}
 
-    unsafe {
-        unsafe extern "C" {
-            static vdso_start: u8;
-            static vdso_end: u8;
+/// Symbol names under which vDSOs export the signal return trampoline.
+const SIGRETURN_SYMBOLS: [&str; 2] = ["__kernel_rt_sigreturn", "__vdso_rt_sigreturn"];
+
+/// Offset of the signal return trampoline within `image`.
+fn sigreturn_offset(image: &VdsoImage<'_>) -> Option<usize> {
+    match SIGRETURN_SYMBOLS
+        .iter()
+        .find_map(|name| image.lookup(name, None))
+    {
+        Some(sym) => {
+            info!("vDSO sigreturn trampoline: {sym}");
+            Some(sym.offset)
         }
-        let (start, end) = (
-            &vdso_start as *const u8 as usize,
-            &vdso_end as *const u8 as usize,
-        );
-        if end > start {
-            sigreturn_offset = Some(crate::config::SIGRETURN_SYM_OFFSET);
+        None => {
+            warn!(
+                "sigreturn trampoline unavailable: vDSO exports none of {SIGRETURN_SYMBOLS:?}, \
+                 signal delivery must use SA_RESTORER"
+            );
+            None
         }
     }
-
-    let sigreturn_offset = sigreturn_offset.unwrap_or_default();
-    let addr = vdso_base + sigreturn_offset;
-    info!(
-        "get_trampoline_addr: vdso_base={:#x}, offset={:#x}, result={:#x}",
-        vdso_base, sigreturn_offset, addr
-    );
-    Some(addr)
 }
 
-#[cfg(target_arch = "x86_64")]
-pub fn get_trampoline_addr(_auxv: &[AuxEntry]) -> Option<usize> {
-    None
+/// Get the user address of the signal return trampoline for a vDSO mapped at
+/// `vdso_base` (the `AT_SYSINFO_EHDR` value of the process).
+///
+/// Returns `None` if the vDSO does not export a trampoline, or the embedded
+/// vDSO is unavailable. The offset is resolved along with the image, so it is
+/// retried under the same conditions.
+pub fn get_trampoline_addr(vdso_base: usize) -> Option<usize> {
+    embedded_vdso()
+        .ok()?
+        .sigreturn
+        .map(|offset| vdso_base + offset)
 }
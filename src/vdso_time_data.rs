@@ -1,12 +1,29 @@
 use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
-use axplat::time::{
-    NANOS_PER_SEC, current_ticks, monotonic_time_nanos, nanos_to_ticks, wall_time_nanos,
-};
+use axplat::time::NANOS_PER_SEC;
 
 const VDSO_BASES: usize = 12;
 
-use crate::config::ClockMode;
+/// Index of the clock serving the high resolution and coarse clocks in
+/// [`VdsoTimeData::clock_data`].
+const CS_HRES_COARSE: usize = 0;
+/// Index of the clock serving `CLOCK_MONOTONIC_RAW` in
+/// [`VdsoTimeData::clock_data`].
+const CS_RAW: usize = 1;
+
+// Clock IDs, which index `VdsoClock::time_data`.
+pub(crate) const CLOCK_REALTIME: usize = 0;
+pub(crate) const CLOCK_MONOTONIC: usize = 1;
+pub(crate) const CLOCK_MONOTONIC_RAW: usize = 4;
+pub(crate) const CLOCK_REALTIME_COARSE: usize = 5;
+pub(crate) const CLOCK_MONOTONIC_COARSE: usize = 6;
+pub(crate) const CLOCK_BOOTTIME: usize = 7;
+pub(crate) const CLOCK_TAI: usize = 11;
+
+use crate::{
+    config::ClockMode,
+    timekeeping::{ClockBase, Timekeeper},
+};
 
 /// vDSO timestamp structure
 #[repr(C)]
@@ -77,8 +94,17 @@ impl VdsoClock {
     }
 }
 
+/// The first vvar page; the vDSO expects it to fill a whole page.
 #[repr(C)]
-#[repr(align(4096))]
+#[cfg_attr(
+    not(any(feature = "page-size-16k", feature = "page-size-64k")),
+    repr(align(4096))
+)]
+#[cfg_attr(
+    all(feature = "page-size-16k", not(feature = "page-size-64k")),
+    repr(align(16384))
+)]
+#[cfg_attr(feature = "page-size-64k", repr(align(65536)))]
 pub struct VdsoTimeData {
     pub clock_data: [VdsoClock; 2],
     pub tz_minuteswest: i32,
@@ -104,97 +130,118 @@ impl VdsoTimeData {
         }
     }
 
-    pub fn update(&mut self) {
-        let cycle_now = current_ticks();
-        let wall_ns = wall_time_nanos();
-        let mono_ns = monotonic_time_nanos();
-        let ticks_per_sec = nanos_to_ticks(NANOS_PER_SEC);
-        let mult_shift = clocks_calc_mult_shift(ticks_per_sec, NANOS_PER_SEC, 10);
-
-        for clk in self.clock_data.iter_mut() {
-            clk.write_seqcount_begin();
-            update_vdso_clock(clk, cycle_now, wall_ns, mono_ns, mult_shift);
-            clk.write_seqcount_end();
-        }
+    /// Publish the clocks of `tk`.
+    pub(crate) fn update(&mut self, tk: &Timekeeper) {
+        let tai_offset_ns = tk.ntp.tai_offset_ns();
+
+        let hres = &mut self.clock_data[CS_HRES_COARSE];
+        hres.write_seqcount_begin();
+        update_vdso_clock(hres, CLOCK_MONOTONIC, &tk.hres_clock());
+        update_hres_bases(hres, tk, tai_offset_ns);
+        hres.write_seqcount_end();
+
+        // The raw clock has its own counter parameters, so that it keeps
+        // counting at the nominal frequency whatever happens to the others.
+        let raw = &mut self.clock_data[CS_RAW];
+        raw.write_seqcount_begin();
+        update_vdso_clock(raw, CLOCK_MONOTONIC_RAW, &tk.raw);
+        raw.write_seqcount_end();
     }
 }
 
-/// Update vDSO clock.
-pub fn update_vdso_clock(
-    clk: &mut VdsoClock,
-    cycle_now: u64,
-    wall_ns: u64,
-    mono_ns: u64,
-    mult_shift: (u32, u32),
-) {
-    let prev_cycle = clk.cycle_last.load(Ordering::Relaxed);
-    let prev_basetime_ns = clk.time_data[1]
-        .sec
-        .wrapping_mul(NANOS_PER_SEC)
-        .wrapping_add(clk.time_data[1].nsec);
-
-    // Check if this is a counter-based clock mode (non-None)
-    let is_counter_mode = clk.clock_mode != (ClockMode::None as i32);
-
-    if is_counter_mode {
+/// Publish `clock` as the counter parameters of `clk` and its base `base`.
+fn update_vdso_clock(clk: &mut VdsoClock, base: usize, clock: &ClockBase) {
+    if clk.clock_mode != (ClockMode::None as i32) {
         // Counter-based modes: Tsc (x86_64), Csr (riscv64/loongarch64), Cntvct
         // (aarch64)
-        if prev_cycle == 0 {
-            let (mult, shift) = mult_shift;
-            clk.mult = mult;
-            clk.shift = shift;
-            clk.time_data[1].sec = mono_ns / NANOS_PER_SEC;
-            clk.time_data[1].nsec = (mono_ns % NANOS_PER_SEC) << shift;
-            clk.cycle_last.store(cycle_now, Ordering::Relaxed);
-        } else {
-            let (mult, shift) = mult_shift;
-            if !(mult == u32::MAX && shift == 0) {
-                clk.mult = mult;
-                clk.shift = shift;
-                clk.time_data[1].sec = mono_ns / NANOS_PER_SEC;
-                clk.time_data[1].nsec = (mono_ns % NANOS_PER_SEC) << shift;
-                clk.cycle_last.store(cycle_now, Ordering::Relaxed);
-            } else {
-                let delta_cycles = (cycle_now.wrapping_sub(prev_cycle)) & clk.mask;
-                let delta_ns = mono_ns.saturating_sub(prev_basetime_ns);
-                if delta_cycles != 0 && delta_ns > 0 {
-                    let (mult, shift) = clocks_calc_mult_shift(delta_cycles, delta_ns, 1);
-                    clk.mult = mult;
-                    clk.shift = shift;
-                    clk.time_data[1].sec = mono_ns / NANOS_PER_SEC;
-                    clk.time_data[1].nsec = (mono_ns % NANOS_PER_SEC) << shift;
-                    clk.cycle_last.store(cycle_now, Ordering::Relaxed);
-                }
-            }
-        }
+        clk.mult = clock.mult;
+        clk.shift = clock.shift;
+        clk.time_data[base].sec = clock.sec;
+        clk.time_data[base].nsec = clock.nsec;
+        clk.cycle_last.store(clock.cycle_last, Ordering::Relaxed);
     } else {
-        // ClockMode::None - No cycle->ns conversion; store direct monotonic ns.
+        // ClockMode::None - No cycle->ns conversion; store direct ns.
         clk.mult = 0;
-        clk.time_data[1].sec = mono_ns / NANOS_PER_SEC;
-        clk.time_data[1].nsec = mono_ns % NANOS_PER_SEC;
+        set_base(&mut clk.time_data[base], clock.ns(), 0);
         clk.cycle_last.store(0, Ordering::Relaxed);
     }
 
-    // Update realtime and boottime entries.
-    let shift = clk.shift;
-    clk.time_data[0].sec = wall_ns / NANOS_PER_SEC;
-    clk.time_data[0].nsec = (wall_ns % NANOS_PER_SEC) << shift;
-    clk.time_data[7].sec = clk.time_data[1].sec;
-    clk.time_data[7].nsec = clk.time_data[1].nsec;
-
     if clk.seq.load(Ordering::Relaxed) < 10 {
-        let cycle_val = clk.cycle_last.load(Ordering::Relaxed);
         log::trace!(
-            "vDSO update: seq={}, cycle_last={}, mono_ns={}, mult={}, shift={}",
+            "vDSO update: seq={}, base={}, cycle_last={}, ns={}, mult={}, shift={}",
             clk.seq.load(Ordering::Relaxed),
-            cycle_val,
-            mono_ns,
+            base,
+            clock.cycle_last,
+            clock.ns(),
             clk.mult,
             clk.shift
         );
     }
 }
 
+/// Update the bases of the high resolution clock `clk` other than
+/// `CLOCK_MONOTONIC`, which is `tk.mono`.
+///
+/// High resolution bases hold nanoseconds shifted left by `clk.shift`, as the
+/// vDSO adds the scaled counter delta before shifting back. They are offset
+/// from `tk.mono` without dropping its fractional nanoseconds, so that they all
+/// advance in step. Coarse bases hold plain nanoseconds and are read as-is, so
+/// they advance once per update, i.e. at tick granularity.
+fn update_hres_bases(clk: &mut VdsoClock, tk: &Timekeeper, tai_offset_ns: i64) {
+    let mono = &tk.mono;
+    let offs_real = tk.offs_real;
+    let offs_tai = offs_real + tai_offset_ns;
+    if clk.clock_mode != (ClockMode::None as i32) {
+        set_offset_base(
+            &mut clk.time_data[CLOCK_REALTIME],
+            mono,
+            tk.offs_real_shifted(),
+        );
+        // Nothing suspends, so boot time and monotonic time are the same.
+        set_offset_base(&mut clk.time_data[CLOCK_BOOTTIME], mono, 0);
+        set_offset_base(
+            &mut clk.time_data[CLOCK_TAI],
+            mono,
+            (offs_tai as i128) << mono.shift,
+        );
+    } else {
+        // Without a counter the vDSO falls back to the syscall and bases are
+        // not shifted.
+        let mono_ns = mono.ns();
+        set_base(
+            &mut clk.time_data[CLOCK_REALTIME],
+            mono_ns.wrapping_add_signed(offs_real),
+            0,
+        );
+        set_base(&mut clk.time_data[CLOCK_BOOTTIME], mono_ns, 0);
+        set_base(
+            &mut clk.time_data[CLOCK_TAI],
+            mono_ns.wrapping_add_signed(offs_tai),
+            0,
+        );
+    }
+    let mono_ns = mono.ns();
+    set_base(
+        &mut clk.time_data[CLOCK_REALTIME_COARSE],
+        mono_ns.wrapping_add_signed(offs_real),
+        0,
+    );
+    set_base(&mut clk.time_data[CLOCK_MONOTONIC_COARSE], mono_ns, 0);
+}
+
+fn set_base(ts: &mut VdsoTimestamp, ns: u64, shift: u32) {
+    ts.sec = ns / NANOS_PER_SEC;
+    ts.nsec = (ns % NANOS_PER_SEC) << shift;
+}
+
+/// Set `ts` to the shifted base of `clock` plus `offset` shifted nanoseconds.
+fn set_offset_base(ts: &mut VdsoTimestamp, clock: &ClockBase, offset: i128) {
+    let one_sec = (NANOS_PER_SEC as i128) << clock.shift;
+    let shifted = clock.sec as i128 * one_sec + clock.nsec as i128 + offset;
+    ts.sec = shifted.div_euclid(one_sec) as u64;
+    ts.nsec = shifted.rem_euclid(one_sec) as u64;
+}
+
 /// Compute multiplier and shift to convert from timer_frequency to
 /// nanos_per_sec.
 pub fn clocks_calc_mult_shift(from: u64, to: u64, maxsec: u32) -> (u32, u32) {
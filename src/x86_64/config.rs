@@ -1,4 +1,4 @@
-pub const VVAR_PAGES: usize = 6;
+include!(concat!(env!("OUT_DIR"), "/vdso_config.rs"));
 pub const PVCLOCK_MAX_CPUS: usize = 128;
 
 #[repr(i32)]
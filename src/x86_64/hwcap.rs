@@ -0,0 +1,31 @@
+use core::arch::x86_64::__cpuid;
+
+/// Red zone below the user stack pointer that signal delivery must skip.
+const SIGNAL_REDZONE: usize = 128;
+
+const HWCAP2_FSGSBASE: usize = 1 << 1;
+const CR4_FSGSBASE: usize = 1 << 16;
+
+/// `AT_HWCAP`: the CPUID.01H:EDX feature flags, as on Linux.
+pub fn hwcap() -> usize {
+    #[allow(unused_unsafe)]
+    let edx = unsafe { __cpuid(1) }.edx;
+    edx as usize
+}
+
+/// `AT_HWCAP2`: `HWCAP2_FSGSBASE` if user space may use `wrfsbase` and
+/// friends.
+pub fn hwcap2() -> usize {
+    let cr4: usize;
+    unsafe { core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack)) };
+    if cr4 & CR4_FSGSBASE != 0 {
+        HWCAP2_FSGSBASE
+    } else {
+        0
+    }
+}
+
+/// `AT_MINSIGSTKSZ` for a signal frame of `frame_size` bytes.
+pub fn minsigstksz(frame_size: usize) -> usize {
+    (frame_size + SIGNAL_REDZONE).next_multiple_of(16)
+}
@@ -1,4 +1,5 @@
 pub mod config;
 pub mod getcpu;
+pub mod hwcap;
 pub mod pvclock_data;
 pub mod vdso_data;
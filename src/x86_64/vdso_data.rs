@@ -1,14 +1,23 @@
 use crate::{
-    config::ClockMode,
+    PAGE_SIZE,
+    config::{ClockMode, VVAR_PAGES},
+    timekeeping::Timekeeper,
     vdso_time_data::VdsoTimeData,
     x86_64::{config::PVCLOCK_MAX_CPUS, pvclock_data::PvClockTimeInfo},
 };
 
+/// Zeroes filling the rest of the vvar area, which is mapped to user space
+/// in full.
+const PAD_SIZE: usize = VVAR_PAGES * PAGE_SIZE
+    - size_of::<VdsoTimeData>()
+    - size_of::<[PvClockTimeInfo; PVCLOCK_MAX_CPUS]>();
+
 #[repr(C)]
 #[repr(align(4096))]
 pub struct VdsoData {
     pub time_data: VdsoTimeData,
     pub pvclock: [PvClockTimeInfo; PVCLOCK_MAX_CPUS],
+    _pad: [u8; PAD_SIZE],
 }
 
 impl Default for VdsoData {
@@ -22,11 +31,12 @@ impl VdsoData {
         Self {
             time_data: VdsoTimeData::new(),
             pvclock: [PvClockTimeInfo::new(); PVCLOCK_MAX_CPUS],
+            _pad: [0u8; PAD_SIZE],
         }
     }
 
-    pub fn time_update(&mut self) {
-        self.time_data.update();
+    pub(crate) fn time_update(&mut self, tk: &Timekeeper) {
+        self.time_data.update(tk);
     }
 
     /// Enable pvclock support.